@@ -0,0 +1,83 @@
+//! Command line tool for inspecting, converting and playing `.hps` files.
+//!
+//! ```text
+//! hps_decode song.hps                  # print metadata
+//! hps_decode song.hps --wav out.wav    # convert to a .wav file
+//! hps_decode song.hps --play --loops 2 # play, looping twice
+//! ```
+
+use clap::Parser;
+use hps_decode::Hps;
+use rodio::{OutputStream, Sink};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Decode and inspect Super Smash Bros. Melee music files"
+)]
+struct Args {
+    /// The .hps file to read
+    file: PathBuf,
+
+    /// Write the decoded audio to a .wav file
+    #[arg(long, value_name = "OUT")]
+    wav: Option<PathBuf>,
+
+    /// Play the song through the default audio output
+    #[arg(long)]
+    play: bool,
+
+    /// When playing, how many times to repeat the looping section before stopping
+    #[arg(long, default_value_t = 0, requires = "play")]
+    loops: u32,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let hps: Hps = std::fs::read(&args.file)?.try_into()?;
+    let audio = hps.decode()?;
+
+    println!("File:        {}", args.file.display());
+    println!("Sample rate: {} Hz", audio.sample_rate);
+    println!("Channels:    {}", audio.channel_count);
+    println!("Blocks:      {}", hps.blocks.len());
+    println!("Duration:    {}", format_duration(audio.duration()));
+    match audio.loop_start_time() {
+        Some(start) => println!(
+            "Loop:        from {} (block {})",
+            format_duration(start),
+            hps.loop_block_index.unwrap_or_default()
+        ),
+        None => println!("Loop:        none"),
+    }
+
+    if let Some(path) = &args.wav {
+        audio.write_wav(BufWriter::new(File::create(path)?))?;
+        println!("Wrote {}", path.display());
+    }
+
+    if args.play {
+        let (_stream, stream_handle) = OutputStream::try_default()?;
+        let sink = Sink::try_new(&stream_handle)?;
+        sink.append(audio.into_rodio_source_looped(args.loops));
+        sink.sleep_until_end();
+    }
+
+    Ok(())
+}
+
+fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    format!(
+        "{}:{:02}.{:03}",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
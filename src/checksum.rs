@@ -0,0 +1,27 @@
+/// A 64-bit [FNV-1a](http://www.isthe.com/chongo/tech/comp/fnv/index.html)
+/// hasher.
+///
+/// Unlike [`std::collections::hash_map::DefaultHasher`], the output of this
+/// hasher is guaranteed to be the same across Rust versions and platforms, so
+/// it's safe to persist.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    pub(crate) fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
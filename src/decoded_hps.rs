@@ -16,7 +16,15 @@
 //! assert_eq!(samples.len(), 6_415_472);
 //! ```
 
-use crate::hps::{Hps, SAMPLES_PER_FRAME};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::checksum::Fnv1a;
+use crate::errors::MixError;
+#[cfg(feature = "cpal-playback")]
+use crate::errors::PlaybackError;
+use crate::hps::{clamp_i16, duration_to_samples, Hps};
 
 /// An iterator over decoded PCM samples.
 ///
@@ -25,7 +33,13 @@ use crate::hps::{Hps, SAMPLES_PER_FRAME};
 pub struct DecodedHps {
     samples: Vec<i16>,
     current_index: usize,
+    /// Number of samples taken from the end with
+    /// [`next_back`](DoubleEndedIterator::next_back)
+    back_index: usize,
     loop_sample_index: Option<usize>,
+    /// The loop point while looping is turned off with
+    /// [`set_looping`](DecodedHps::set_looping)
+    suspended_loop_sample_index: Option<usize>,
     /// Number of samples per second per audio channel
     pub sample_rate: u32,
     /// Number of audio channels
@@ -36,6 +50,9 @@ impl Iterator for DecodedHps {
     type Item = i16;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.loop_sample_index.is_none() && self.remaining_samples() == 0 {
+            return None;
+        }
         match (self.samples.get(self.current_index), self.loop_sample_index) {
             // If there are more samples to play, return the next one
             (Some(&sample), _) => {
@@ -52,57 +69,1192 @@ impl Iterator for DecodedHps {
             (None, None) => None,
         }
     }
+
+    /// Skips ahead in constant time, wrapping around the loop section as many
+    /// times as needed for looping songs.
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        let Some(loop_sample_index) = self.loop_sample_index else {
+            let remaining = self.remaining_samples();
+            if n >= remaining {
+                self.current_index += remaining;
+                return None;
+            }
+            self.current_index += n + 1;
+            return Some(self.samples[self.current_index - 1]);
+        };
+
+        let index = match self.current_index.checked_add(n) {
+            Some(index) if index < self.samples.len() => index,
+            _ => {
+                let loop_len = self.samples.len() - loop_sample_index;
+                if loop_len == 0 {
+                    return None;
+                }
+                // How far past the end of the song the sample is, wrapped into
+                // the loop section
+                let past_end = n - (self.samples.len() - self.current_index);
+                loop_sample_index + past_end % loop_len
+            }
+        };
+        self.current_index = index + 1;
+        Some(self.samples[index])
+    }
+
+    /// Songs that don't loop report exactly how many samples are left. Looping
+    /// songs never end, so they report `(usize::MAX, None)`.
+    ///
+    /// Whether a song loops is only known at runtime, so `DecodedHps` can't
+    /// implement [`ExactSizeIterator`]. Use
+    /// [`loop_then_fade_out`](DecodedHps::loop_then_fade_out) to get a
+    /// [`FiniteLoop`], which does.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.loop_sample_index {
+            Some(_) => (usize::MAX, None),
+            None => {
+                let remaining = self.remaining_samples();
+                (remaining, Some(remaining))
+            }
+        }
+    }
+}
+
+/// Songs that don't loop can be played backwards. Samples are yielded one
+/// frame at a time from the end, with the audio channels in each frame kept in
+/// their usual order, so a stereo song stays in stereo instead of swapping
+/// channels.
+///
+/// Looping songs have no end, so `next_back` always returns `None` for them.
+///
+/// When mixing [`next`](Iterator::next) and `next_back`, both ends are only
+/// exact if they meet on a frame boundary.
+impl DoubleEndedIterator for DecodedHps {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.loop_sample_index.is_some() || self.remaining_samples() == 0 {
+            return None;
+        }
+        let channel_count = self.channel_count as usize;
+        let frame = self.samples.len() / channel_count - 1 - self.back_index / channel_count;
+        let sample = self.samples[frame * channel_count + self.back_index % channel_count];
+        self.back_index += 1;
+        Some(sample)
+    }
 }
 
 impl DecodedHps {
     pub(crate) fn new(hps: &Hps, samples: Vec<i16>) -> Self {
-        let loop_sample_index = hps.loop_block_index.map(|index| {
-            hps.blocks[..index]
-                .iter()
-                .map(|b| b.frames.len())
-                .sum::<usize>()
-                * SAMPLES_PER_FRAME
-        });
+        let loop_sample_index = hps
+            .loop_block_index
+            .map(|index| hps.samples_before_block(index) * hps.channel_count as usize);
 
         Self {
             samples,
             current_index: 0,
+            back_index: 0,
             loop_sample_index,
+            suspended_loop_sample_index: None,
             sample_rate: hps.sample_rate,
             channel_count: hps.channel_count,
         }
     }
 
+    /// Number of samples left to play before the song either ends or jumps back
+    /// to the loop point. Playback is split into an intro span (from the start
+    /// to the end of the song) followed by the loop span repeating forever, so
+    /// rodio can treat each one as a run of samples with no discontinuity.
+    #[cfg(any(feature = "rodio-source", test))]
+    pub(crate) fn samples_until_span_end(&self) -> usize {
+        match self.loop_sample_index {
+            Some(loop_sample_index) => match self.samples.len() - self.current_index {
+                0 => self.samples.len() - loop_sample_index,
+                remaining => remaining,
+            },
+            None => self.remaining_samples(),
+        }
+    }
+
+    /// Number of samples left to play before a non-looping song ends
+    fn remaining_samples(&self) -> usize {
+        self.samples
+            .len()
+            .saturating_sub(self.current_index + self.back_index)
+    }
+
+    /// Create a non-looping `DecodedHps` from interleaved samples
+    pub(crate) fn from_samples(samples: Vec<i16>, sample_rate: u32, channel_count: u32) -> Self {
+        Self {
+            samples,
+            current_index: 0,
+            back_index: 0,
+            loop_sample_index: None,
+            suspended_loop_sample_index: None,
+            sample_rate,
+            channel_count,
+        }
+    }
+
+    /// Create a non-looping `DecodedHps` with the same sample rate and channel
+    /// count as `self`
+    fn with_samples(&self, samples: Vec<i16>) -> Self {
+        Self::from_samples(samples, self.sample_rate, self.channel_count)
+    }
+
+    /// Number of samples per channel that play over the given duration
+    fn duration_to_frames(&self, duration: Duration) -> usize {
+        duration_to_samples(duration, self.sample_rate)
+    }
+
     /// Get the underlying decoded PCM samples as a slice.
     pub fn samples(&self) -> &[i16] {
         &self.samples
     }
 
+    /// Get the samples of a single audio channel (`0` for left, `1` for
+    /// right), or `None` if the song doesn't have that channel.
+    pub fn channel_samples(&self, channel: usize) -> Option<Vec<i16>> {
+        let channel_count = self.channel_count as usize;
+        (channel < channel_count).then(|| {
+            self.samples
+                .iter()
+                .skip(channel)
+                .step_by(channel_count)
+                .copied()
+                .collect()
+        })
+    }
+
+    /// Get the samples of each audio channel separately, rather than
+    /// interleaved.
+    pub fn planar_samples(&self) -> Vec<Vec<i16>> {
+        (0..self.channel_count as usize)
+            .filter_map(|channel| self.channel_samples(channel))
+            .collect()
+    }
+
+    /// Get the interleaved samples as `f32`s in the range `[-1.0, 1.0]`, for
+    /// audio pipelines that work in floating point. `i16::MIN` maps to exactly
+    /// `-1.0`.
+    pub fn samples_f32(&self) -> Vec<f32> {
+        self.samples.iter().map(|&s| sample_to_f32(s)).collect()
+    }
+
+    /// Convert the song to `target_rate` samples per second with linear
+    /// interpolation. Each audio channel is resampled separately, and the loop
+    /// point is moved to the matching sample at the new rate.
+    ///
+    /// The returned song starts from the beginning.
+    #[cfg(feature = "resample")]
+    pub fn resample(&self, target_rate: u32) -> DecodedHps {
+        let channel_count = self.channel_count as usize;
+        let source_rate = self.sample_rate as u64;
+        let target_rate_u64 = target_rate as u64;
+        let frame_count = (self.samples.len() / channel_count) as u64;
+        let resampled_frame_count = (frame_count * target_rate_u64).div_ceil(source_rate);
+
+        let channels = self
+            .planar_samples()
+            .into_iter()
+            .map(|samples| {
+                (0..resampled_frame_count)
+                    .map(|frame| {
+                        // Position of the new sample in the old samples, as a
+                        // whole index plus a fraction of `target_rate`
+                        let position = frame * source_rate;
+                        let index = (position / target_rate_u64) as usize;
+                        let fraction = (position % target_rate_u64) as i64;
+
+                        let current = samples[index] as i64;
+                        let next = samples.get(index + 1).map_or(current, |&s| s as i64);
+                        ((current * (target_rate_u64 as i64 - fraction) + next * fraction)
+                            / target_rate_u64 as i64) as i16
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let samples = (0..resampled_frame_count as usize)
+            .flat_map(|frame| channels.iter().map(move |channel| channel[frame]))
+            .collect();
+
+        // Round the loop point to the nearest sample at the new rate, without
+        // rounding past the end of the song
+        let last_frame = (resampled_frame_count as usize).saturating_sub(1);
+        let resample_index = |index: usize| {
+            let frame = (index / channel_count) as u64;
+            let frame = ((frame * target_rate_u64 + source_rate / 2) / source_rate) as usize;
+            frame.min(last_frame) * channel_count
+        };
+
+        DecodedHps {
+            samples,
+            current_index: 0,
+            back_index: 0,
+            loop_sample_index: self.loop_sample_index.map(resample_index),
+            suspended_loop_sample_index: self.suspended_loop_sample_index.map(resample_index),
+            sample_rate: target_rate,
+            channel_count: self.channel_count,
+        }
+    }
+
+    /// Check whether the samples match a reference dump of big-endian 16-bit
+    /// PCM samples, such as `test-data/test-song-decoded.bin`.
+    #[cfg(feature = "test-util")]
+    pub fn matches_reference_be(&self, bytes: &[u8]) -> bool {
+        bytes.len() == self.samples.len() * 2
+            && bytes
+                .chunks_exact(2)
+                .zip(&self.samples)
+                .all(|(chunk, &sample)| chunk == sample.to_be_bytes())
+    }
+
+    /// Write the samples to `writer` as a 16-bit PCM `.wav` file. Looping songs
+    /// are written once through, without looping.
+    ///
+    /// The sizes in the header are filled in once every sample is written,
+    /// which is why `writer` needs to be seekable. Songs too long for the
+    /// header's 32-bit sizes are rejected with [`std::io::ErrorKind::InvalidInput`].
+    ///
+    /// ```
+    /// let file = std::fs::File::create("./respect-your-elders.wav")?;
+    /// audio.write_wav(std::io::BufWriter::new(file))?;
+    /// ```
+    #[cfg(feature = "wav")]
+    pub fn write_wav<W: std::io::Write + std::io::Seek>(&self, writer: W) -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        const BYTES_PER_SAMPLE: u64 = 2;
+        let invalid_input = |error| match error {
+            hound::Error::IoError(error) => error,
+            error => Error::new(ErrorKind::InvalidInput, error),
+        };
+
+        // The RIFF and data chunk sizes and the byte rate are all u32s
+        let data_len = self.samples.len() as u64 * BYTES_PER_SAMPLE;
+        let byte_rate = self.sample_rate as u64 * self.channel_count as u64 * BYTES_PER_SAMPLE;
+        if data_len + 36 > u32::MAX as u64 || byte_rate > u32::MAX as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The song is too long or its sample rate is too high for a .wav file",
+            ));
+        }
+
+        let spec = hound::WavSpec {
+            channels: self.channel_count as u16,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = hound::WavWriter::new(writer, spec).map_err(invalid_input)?;
+        for &sample in &self.samples {
+            wav.write_sample(sample).map_err(invalid_input)?;
+        }
+        wav.finalize().map_err(invalid_input)
+    }
+
+    /// Returns an estimate of how many bytes of memory this `DecodedHps`
+    /// occupies, including its sample buffer. See also
+    /// [`Hps::approx_memory_bytes`].
+    pub fn memory_bytes(&self) -> usize {
+        core::mem::size_of::<Self>() + self.samples.capacity() * core::mem::size_of::<i16>()
+    }
+
+    /// Convert the samples to unsigned 8-bit PCM, as used by 8-bit WAV files.
+    ///
+    /// When `dither` is `true`, triangular (TPDF) dither is added before the
+    /// samples are reduced to 8 bits, which trades quantization distortion for
+    /// a low level of noise. The dither noise comes from a fixed-seed generator,
+    /// so the output is still the same every time.
+    pub fn to_u8_pcm(&self, dither: bool) -> Vec<u8> {
+        let mut rng = XorShift32(0x2545_f491);
+        self.samples
+            .iter()
+            .map(|&sample| {
+                let mut sample = sample as i32;
+                if dither {
+                    // The difference of two uniform values between 0 and one
+                    // 8-bit step has a triangular distribution
+                    let step = 1 << 8;
+                    sample += (rng.next() % step) as i32 - (rng.next() % step) as i32;
+                }
+                // Round to the nearest 8-bit step, rather than always down
+                let sample = ((sample + (1 << 7)) >> 8).clamp(i8::MIN as i32, i8::MAX as i32);
+                (sample + 128) as u8
+            })
+            .collect()
+    }
+
     /// Returns `true` if the song loops. If this is the case, it's an _infinite_ iterator.
     pub fn is_looping(&self) -> bool {
         self.loop_sample_index.is_some()
     }
 
+    /// Turn looping off or back on. While it's off, the song plays through
+    /// once and then ends, as if it didn't loop. Songs that don't have a loop
+    /// point never loop, even if this is set to `true`.
+    pub fn set_looping(&mut self, looping: bool) {
+        if looping {
+            self.loop_sample_index = self
+                .loop_sample_index
+                .or(self.suspended_loop_sample_index.take());
+        } else if let Some(loop_sample_index) = self.loop_sample_index.take() {
+            self.suspended_loop_sample_index = Some(loop_sample_index);
+        }
+    }
+
+    /// Returns the point in the song that playback jumps back to after reaching
+    /// the end, or `None` if the song doesn't loop.
+    pub fn loop_start_time(&self) -> Option<Duration> {
+        self.loop_sample_index
+            .map(|index| self.sample_index_to_duration(index))
+    }
+
+    /// Get the interleaved samples before the loop point, which play only once.
+    /// For songs that don't loop, this is every sample.
+    pub fn intro_samples(&self) -> &[i16] {
+        &self.samples[..self.loop_sample_index.unwrap_or(self.samples.len())]
+    }
+
+    /// Get the interleaved samples from the loop point to the end of the song,
+    /// which repeat forever, or `None` if the song doesn't loop.
+    pub fn loop_samples(&self) -> Option<&[i16]> {
+        self.loop_sample_index.map(|index| &self.samples[index..])
+    }
+
+    /// Jump to the loop point, skipping the rest of the intro. Does nothing if
+    /// the song doesn't loop.
+    pub fn skip_to_loop(&mut self) {
+        if let Some(loop_sample_index) = self.loop_sample_index {
+            self.current_index = loop_sample_index;
+        }
+    }
+
+    /// Returns how far into the song the iterator currently is. For looping
+    /// songs, this jumps back to the [loop start](DecodedHps::loop_start_time)
+    /// each time the song loops.
+    pub fn position(&self) -> Duration {
+        self.sample_index_to_duration(self.current_index)
+    }
+
+    /// Jump to the given point in the song, and return the point that was
+    /// actually jumped to. See [`seek_to_sample`](DecodedHps::seek_to_sample).
+    pub fn seek(&mut self, position: Duration) -> Duration {
+        self.seek_to_sample(self.duration_to_frames(position));
+        self.sample_index_to_duration(self.current_index)
+    }
+
+    /// Jump to the sample at `index` (per channel), and return the index that
+    /// was actually jumped to. Playback always resumes at the first audio
+    /// channel, so the channels stay in sync.
+    ///
+    /// For looping songs, seeking past the end wraps around the looping
+    /// section, as if the song had been played up to that point. Otherwise,
+    /// seeking past the end jumps to the end.
+    ///
+    /// Samples already taken from the end with
+    /// [`next_back`](DoubleEndedIterator::next_back) are played again after
+    /// seeking, so playback runs to the real end of the song.
+    pub fn seek_to_sample(&mut self, index: usize) -> usize {
+        let channel_count = self.channel_count as usize;
+        let frame_count = self.samples.len() / channel_count;
+
+        let index = match self.loop_sample_index {
+            Some(loop_sample_index) if index >= frame_count => {
+                let loop_start = loop_sample_index / channel_count;
+                let loop_len = frame_count - loop_start;
+                if loop_len == 0 {
+                    loop_start
+                } else {
+                    loop_start + (index - frame_count) % loop_len
+                }
+            }
+            _ => index.min(frame_count),
+        };
+
+        self.current_index = index * channel_count;
+        self.back_index = 0;
+        index
+    }
+
+    /// Convert an index into the interleaved samples into a point in time
+    fn sample_index_to_duration(&self, index: usize) -> Duration {
+        let samples_per_channel = (index / self.channel_count as usize) as u64;
+        Duration::from_nanos(samples_per_channel * 1_000_000_000 / self.sample_rate as u64)
+    }
+
+    /// Multiply each audio channel by its own gain, e.g. `&[1.0, 1.5]` to boost
+    /// the right channel of a stereo song by 50%. Samples that would overflow
+    /// are clamped.
+    ///
+    /// Returns an error if there isn't exactly one gain per channel.
+    pub fn set_channel_gains(&mut self, gains: &[f32]) -> Result<(), MixError> {
+        if gains.len() != self.channel_count as usize {
+            return Err(MixError::WrongChannelValueCount(
+                self.channel_count,
+                gains.len(),
+            ));
+        }
+
+        for channel_samples in self.samples.chunks_mut(gains.len()) {
+            for (sample, &gain) in channel_samples.iter_mut().zip(gains) {
+                *sample = clamp_i16((*sample as f32 * gain) as i32);
+            }
+        }
+        Ok(())
+    }
+
+    /// Multiply every sample by `factor`. Samples that would overflow are
+    /// clamped, and a negative `factor` inverts the phase.
+    ///
+    /// To normalize songs, pick a factor based on their
+    /// [`peak_amplitude`](DecodedHps::peak_amplitude) or
+    /// [`rms_amplitude`](DecodedHps::rms_amplitude).
+    pub fn apply_gain(&mut self, factor: f32) {
+        for sample in &mut self.samples {
+            *sample = clamp_i16((*sample as f32 * factor) as i32);
+        }
+    }
+
+    /// Returns the largest absolute sample value across every channel, over
+    /// the song as it plays through once. A sample of `i16::MIN` counts as
+    /// `i16::MAX`.
+    pub fn peak_amplitude(&self) -> i16 {
+        self.peak_per_channel()
+            .into_iter()
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Returns the largest absolute sample value of each audio channel, like
+    /// [`peak_amplitude`](DecodedHps::peak_amplitude).
+    pub fn peak_per_channel(&self) -> Vec<i16> {
+        let mut peaks = vec![0; self.channel_count as usize];
+        for frame in self.samples.chunks_exact(peaks.len()) {
+            for (peak, sample) in peaks.iter_mut().zip(frame) {
+                *peak = (*peak).max(sample.saturating_abs());
+            }
+        }
+        peaks
+    }
+
+    /// Returns the root mean square of every sample across every channel, over
+    /// the song as it plays through once, in the same units as the samples.
+    /// Empty songs return `0.0`.
+    #[cfg(feature = "std")]
+    pub fn rms_amplitude(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        // Each square is at most 2^30, so this can't overflow for any song
+        // shorter than 2^34 samples
+        let sum_of_squares = self
+            .samples
+            .iter()
+            .map(|&sample| (sample as i64 * sample as i64) as u64)
+            .sum::<u64>();
+        (sum_of_squares as f64 / self.samples.len() as f64).sqrt() as f32
+    }
+
+    /// Linearly fade in the first `duration` of the song, from silence up to
+    /// full volume. If the song is shorter than `duration`, the whole song is
+    /// faded.
+    ///
+    /// The samples themselves are changed, so for looping songs whose loop
+    /// starts within the fade, the faded part is heard again every loop.
+    pub fn apply_fade_in(&mut self, duration: Duration) {
+        let channel_count = self.channel_count as usize;
+        let fade_frames = self
+            .duration_to_frames(duration)
+            .min(self.samples.len() / channel_count);
+
+        for (position, frame) in self
+            .samples
+            .chunks_exact_mut(channel_count)
+            .take(fade_frames)
+            .enumerate()
+        {
+            for sample in frame {
+                *sample = (*sample as i64 * position as i64 / fade_frames as i64) as i16;
+            }
+        }
+    }
+
+    /// Linearly fade out the last `duration` of the song, from full volume
+    /// down to silence. If the song is shorter than `duration`, the whole song
+    /// is faded.
+    ///
+    /// Only the song as it plays through once is faded. For looping songs, the
+    /// faded part is the end of the looping section, so it's heard again every
+    /// loop; to fade a looping song out after a number of loops, use
+    /// [`loop_then_fade_out`](DecodedHps::loop_then_fade_out) instead.
+    pub fn apply_fade_out(&mut self, duration: Duration) {
+        let channel_count = self.channel_count as usize;
+        let frame_count = self.samples.len() / channel_count;
+        let fade_frames = self.duration_to_frames(duration).min(frame_count);
+
+        // The gain steps down to exactly 0 on the last frame, and a one frame
+        // fade just silences that frame
+        let last_position = fade_frames.saturating_sub(1) as i64;
+        for (position, frame) in self
+            .samples
+            .chunks_exact_mut(channel_count)
+            .skip(frame_count - fade_frames)
+            .enumerate()
+        {
+            let remaining = last_position - position as i64;
+            for sample in frame {
+                *sample = (*sample as i64 * remaining)
+                    .checked_div(last_position)
+                    .unwrap_or(0) as i16;
+            }
+        }
+    }
+
+    /// Measure the integrated loudness of the song (once through, ignoring
+    /// looping) in LUFS, following ITU-R BS.1770: each channel is K-weighted,
+    /// then the mean power of overlapping 400ms blocks is gated at -70 LUFS
+    /// and again at 10 LU below the result. Every channel is weighted equally.
+    ///
+    /// Returns [`f64::NEG_INFINITY`] if the song is silent or shorter than a
+    /// single block.
+    #[cfg(feature = "std")]
+    pub fn integrated_loudness(&self) -> f64 {
+        let channel_count = self.channel_count as usize;
+        let frame_count = self.samples.len() / channel_count;
+
+        // K-weighted power of each frame, summed across every channel
+        let mut frame_powers = vec![0.0; frame_count];
+        for channel in 0..channel_count {
+            let mut shelf = Biquad::k_weighting_shelf(self.sample_rate as f64);
+            let mut high_pass = Biquad::k_weighting_high_pass(self.sample_rate as f64);
+            let channel_samples = self.samples.iter().skip(channel).step_by(channel_count);
+            for (power, &sample) in frame_powers.iter_mut().zip(channel_samples) {
+                let filtered = high_pass.process(shelf.process(sample as f64 / 32768.0));
+                *power += filtered * filtered;
+            }
+        }
+
+        // Mean power of 400ms blocks that overlap by 75%
+        let block_len = self.sample_rate as usize * 2 / 5;
+        let step = (block_len / 4).max(1);
+        if block_len == 0 || frame_count < block_len {
+            return f64::NEG_INFINITY;
+        }
+        let mut prefix_sums = Vec::with_capacity(frame_count + 1);
+        prefix_sums.push(0.0);
+        for power in frame_powers {
+            prefix_sums.push(prefix_sums.last().copied().unwrap_or_default() + power);
+        }
+        let block_powers = (0..=frame_count - block_len)
+            .step_by(step)
+            .map(|start| (prefix_sums[start + block_len] - prefix_sums[start]) / block_len as f64)
+            .collect::<Vec<_>>();
+
+        let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+        let gated_loudness = |threshold: f64| {
+            let gated = block_powers
+                .iter()
+                .filter(|&&power| loudness(power) > threshold)
+                .collect::<Vec<_>>();
+            loudness(gated.iter().copied().sum::<f64>() / gated.len() as f64)
+        };
+
+        let relative_threshold = gated_loudness(-70.0) - 10.0;
+        if relative_threshold.is_nan() {
+            return f64::NEG_INFINITY;
+        }
+        gated_loudness(relative_threshold.max(-70.0))
+    }
+
+    /// Downmix the song to a single audio channel by averaging the channels
+    /// together. The loop point and playback position are kept.
+    pub fn into_mono(self) -> DecodedHps {
+        let channel_count = self.channel_count as usize;
+        if channel_count == 1 {
+            return self;
+        }
+
+        let samples = self
+            .samples
+            .chunks_exact(channel_count)
+            .map(|channels| {
+                let sum = channels.iter().map(|&s| s as i32).sum::<i32>();
+                (sum / channel_count as i32) as i16
+            })
+            .collect();
+
+        DecodedHps {
+            samples,
+            current_index: self.current_index / channel_count,
+            back_index: self.back_index / channel_count,
+            loop_sample_index: self.loop_sample_index.map(|index| index / channel_count),
+            suspended_loop_sample_index: self
+                .suspended_loop_sample_index
+                .map(|index| index / channel_count),
+            sample_rate: self.sample_rate,
+            channel_count: 1,
+        }
+    }
+
+    /// Returns `true` if this is a stereo song whose left and right channels
+    /// never differ by more than `tolerance`, meaning it's effectively mono.
+    pub fn is_dual_mono(&self, tolerance: i16) -> bool {
+        self.channel_count == 2
+            && self
+                .samples
+                .chunks_exact(2)
+                .all(|pair| (pair[0] as i32 - pair[1] as i32).abs() <= tolerance as i32)
+    }
+
+    /// Play the song, loop it `loops` more times, then fade out over `fade`
+    /// before stopping. The fade begins where the next loop would have
+    /// started, so the song never cuts off abruptly.
+    ///
+    /// Playback always starts from the beginning of the song. For songs that
+    /// don't loop, `loops` is ignored and the last `fade` of the song is faded
+    /// out instead.
+    pub fn loop_then_fade_out(self, loops: u32, fade: Duration) -> FiniteLoop {
+        let channel_count = self.channel_count as usize;
+        let fade_len = self.duration_to_frames(fade) * channel_count;
+
+        match self.loop_sample_index {
+            Some(loop_sample_index) => {
+                let loop_len = self.samples.len() - loop_sample_index;
+                let total_len = self.samples.len() + loops as usize * loop_len + fade_len;
+                FiniteLoop::new(self, total_len, fade_len)
+            }
+            None => {
+                let total_len = self.samples.len();
+                FiniteLoop::new(self, total_len, fade_len.min(total_len))
+            }
+        }
+    }
+
+    /// Convert the song into a rodio source that plays through once, then
+    /// repeats the looping section `max_loops` more times before ending, so
+    /// that it has a finite [`total_duration`](rodio::Source::total_duration).
+    /// A `max_loops` of `0` plays the song through once without looping.
+    ///
+    /// This is the same as [`loop_then_fade_out`](DecodedHps::loop_then_fade_out)
+    /// without the fade.
+    #[cfg(feature = "rodio-source")]
+    pub fn into_rodio_source_looped(self, max_loops: u32) -> FiniteLoop {
+        self.loop_then_fade_out(max_loops, Duration::ZERO)
+    }
+
+    /// Play the song from its current position on the default audio output
+    /// device with [cpal](https://docs.rs/cpal), blocking until it ends.
+    /// Looping songs never end, so this only returns for them if playback
+    /// fails.
+    ///
+    /// The output device must support the song's channel count. If it can't
+    /// play the song's sample rate, the song is resampled to the device's
+    /// default sample rate first. Samples are converted to whatever format the
+    /// device uses.
+    #[cfg(feature = "cpal-playback")]
+    pub fn play_blocking(&self) -> Result<(), PlaybackError> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or(PlaybackError::NoOutputDevice)?;
+        let find_config = |sample_rate: cpal::SampleRate| {
+            Ok::<_, PlaybackError>(
+                device
+                    .supported_output_configs()?
+                    .find(|config| {
+                        config.channels() as u32 == self.channel_count
+                            && (config.min_sample_rate()..=config.max_sample_rate())
+                                .contains(&sample_rate)
+                    })
+                    .map(|config| config.with_sample_rate(sample_rate)),
+            )
+        };
+
+        let (audio, config) = match find_config(cpal::SampleRate(self.sample_rate))? {
+            Some(config) => (self.clone(), config),
+            None => {
+                let sample_rate = device.default_output_config()?.sample_rate();
+                let config = find_config(sample_rate)?.ok_or(PlaybackError::UnsupportedConfig(
+                    self.channel_count,
+                    self.sample_rate,
+                ))?;
+                let mut audio = self.resample(sample_rate.0);
+                audio.seek(self.position());
+                (audio, config)
+            }
+        };
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::I16 => {
+                Self::build_cpal_stream::<i16>(audio, &device, &config, sender)
+            }
+            cpal::SampleFormat::I32 => {
+                Self::build_cpal_stream::<i32>(audio, &device, &config, sender)
+            }
+            cpal::SampleFormat::U16 => {
+                Self::build_cpal_stream::<u16>(audio, &device, &config, sender)
+            }
+            cpal::SampleFormat::F32 => {
+                Self::build_cpal_stream::<f32>(audio, &device, &config, sender)
+            }
+            cpal::SampleFormat::F64 => {
+                Self::build_cpal_stream::<f64>(audio, &device, &config, sender)
+            }
+            format => return Err(PlaybackError::UnsupportedSampleFormat(format)),
+        }?;
+        stream.play()?;
+
+        // Wait for the song to end or the stream to fail. If the stream is
+        // dropped without either, the channel disconnects and playback is over.
+        let buffer_duration = receiver.recv().unwrap_or(Ok(Duration::ZERO))?;
+
+        // The device may still be playing the buffer that held the last
+        // samples, so give it time to finish before the stream is dropped
+        std::thread::sleep(buffer_duration);
+        Ok(())
+    }
+
+    /// Build a cpal output stream that plays `audio`, then sends how long one
+    /// buffer takes to play on `done` once every sample has been handed to the
+    /// device, or the error if the stream fails.
+    #[cfg(feature = "cpal-playback")]
+    fn build_cpal_stream<T: cpal::SizedSample + cpal::FromSample<i16>>(
+        mut audio: DecodedHps,
+        device: &cpal::Device,
+        config: &cpal::SupportedStreamConfig,
+        done: std::sync::mpsc::Sender<Result<Duration, cpal::StreamError>>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        use cpal::traits::DeviceTrait;
+
+        let error_done = done.clone();
+        device.build_output_stream(
+            &config.config(),
+            move |data: &mut [T], _| {
+                if audio.fill_output_buffer(data, T::from_sample, T::EQUILIBRIUM) {
+                    let frames = (data.len() / audio.channel_count as usize) as u64;
+                    let nanos = frames * 1_000_000_000 / audio.sample_rate as u64;
+                    let _ = done.send(Ok(Duration::from_nanos(nanos)));
+                }
+            },
+            move |error| {
+                let _ = error_done.send(Err(error));
+            },
+            None,
+        )
+    }
+
+    /// Fill an audio output buffer with the next samples, converted with
+    /// `convert`, padding it with `silence` once the song has ended.
+    ///
+    /// Returns `true` if the song had already ended before this buffer, which
+    /// means every sample has been handed to the output device in an earlier
+    /// buffer. The buffer that ends the song is padded but doesn't count, since
+    /// it still has to be played.
+    #[cfg(any(feature = "cpal-playback", test))]
+    pub(crate) fn fill_output_buffer<T: Copy>(
+        &mut self,
+        data: &mut [T],
+        convert: impl Fn(i16) -> T,
+        silence: T,
+    ) -> bool {
+        let finished = self.size_hint() == (0, Some(0));
+        for sample in data.iter_mut() {
+            *sample = self.next().map_or(silence, &convert);
+        }
+        finished
+    }
+
+    /// Convert the song into a rodio
+    /// [`SamplesBuffer`](rodio::buffer::SamplesBuffer) of `f32` samples in the
+    /// range `[-1.0, 1.0]`.
+    ///
+    /// Unlike a `DecodedHps`, a `SamplesBuffer` can be cloned and replayed, but
+    /// it does not loop.
+    #[cfg(feature = "rodio-source")]
+    pub fn into_samples_buffer(self) -> rodio::buffer::SamplesBuffer<f32> {
+        let samples = self.samples_f32();
+        rodio::buffer::SamplesBuffer::new(self.channel_count as u16, self.sample_rate, samples)
+    }
+
+    /// Returns a hash of the audio, which can be used to tell whether two files
+    /// decode to the same song even if their blocks are laid out differently.
+    ///
+    /// The hash covers, in order: the sample rate, channel count, loop point
+    /// (as an interleaved sample index), and every sample. Playback position is
+    /// _not_ included. It's computed with 64-bit FNV-1a over the big-endian
+    /// bytes of each value, so it's stable across platforms and versions of
+    /// this library.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+
+        hasher.write(&self.sample_rate.to_be_bytes());
+        hasher.write(&self.channel_count.to_be_bytes());
+
+        // A non-looping song is hashed as a loop point of u64::MAX
+        let loop_sample_index = self.loop_sample_index.map_or(u64::MAX, |i| i as u64);
+        hasher.write(&loop_sample_index.to_be_bytes());
+
+        for sample in &self.samples {
+            hasher.write(&sample.to_be_bytes());
+        }
+
+        hasher.finish()
+    }
+
     /// Returns the total duration of the song without any looping.
-    pub fn duration(&self) -> std::time::Duration {
+    pub fn duration(&self) -> Duration {
         let sample_count = self.samples.len() as u64;
         let samples_per_second = (self.sample_rate * self.channel_count) as u64;
-        std::time::Duration::from_millis(1000 * sample_count / samples_per_second)
+        Duration::from_millis(1000 * sample_count / samples_per_second)
+    }
+
+    /// Returns the length of the looping section of the song, from the
+    /// [loop start](DecodedHps::loop_start_time) to the end, or `None` if the
+    /// song doesn't loop.
+    pub fn loop_section_duration(&self) -> Option<Duration> {
+        self.loop_sample_index
+            .map(|index| self.sample_index_to_duration(self.samples.len() - index))
+    }
+
+    /// Returns how long playback takes if the song plays through once, then
+    /// repeats the looping section `loops` more times, as with
+    /// [`loop_then_fade_out`](DecodedHps::loop_then_fade_out) (without the
+    /// fade).
+    ///
+    /// For songs that don't loop, this is the same as
+    /// [`duration`](DecodedHps::duration), regardless of `loops`.
+    pub fn looped_duration(&self, loops: u32) -> Duration {
+        let loop_section = self.loop_section_duration().unwrap_or_default();
+        self.duration() + loop_section * loops
+    }
+
+    /// Returns the length of the song without any looping, measured in game
+    /// frames at the given frame rate (e.g. `60.0` for Melee). The result is
+    /// fractional, so it can be used to line audio up with gameplay exactly.
+    pub fn duration_in_game_frames(&self, fps: f64) -> f64 {
+        let samples_per_channel = (self.samples.len() / self.channel_count as usize) as f64;
+        samples_per_channel / self.sample_rate as f64 * fps
+    }
+
+    /// Summarize the song as `buckets` `(min, max)` sample pairs, suitable for
+    /// drawing a waveform. Looping is ignored.
+    ///
+    /// Each pair covers an equal share of the song across all channels. If
+    /// `buckets` is larger than the number of samples per channel, the pairs
+    /// that don't cover any samples are `(0, 0)`. This gives the same result
+    /// as [`Hps::thumbnail`], without decoding the song again.
+    pub fn waveform_peaks(&self, buckets: usize) -> Vec<(i16, i16)> {
+        let channel_count = self.channel_count as usize;
+        let frame_count = self.samples.len() / channel_count;
+
+        let mut peaks = vec![(i16::MAX, i16::MIN); buckets];
+        if buckets > 0 {
+            for (i, frame) in self.samples.chunks(channel_count).enumerate() {
+                let (min, max) = &mut peaks[i * buckets / frame_count];
+                for &sample in frame {
+                    *min = sample.min(*min);
+                    *max = sample.max(*max);
+                }
+            }
+        }
+
+        peaks
+            .into_iter()
+            .map(|(min, max)| if min > max { (0, 0) } else { (min, max) })
+            .collect()
+    }
+
+    /// Split the samples into interleaved buffers holding `frames` samples for
+    /// each audio channel, as requested by callback-based audio APIs like
+    /// cpal or SDL. The last buffer may be shorter.
+    ///
+    /// This makes a single pass over the samples, even for looping songs.
+    pub fn chunks(&self, frames: usize) -> impl Iterator<Item = &[i16]> + '_ {
+        self.samples
+            .chunks(frames.max(1) * self.channel_count as usize)
+    }
+
+    /// Split the song into windows that are each `window` long, and yield the
+    /// peak level of each audio channel within every window. Useful for driving
+    /// level meters during playback.
+    ///
+    /// Peaks are absolute values, so `i16::MIN` is reported as `i16::MAX`.
+    pub fn meter_frames(&self, window: Duration) -> impl Iterator<Item = Vec<i16>> + '_ {
+        let channel_count = self.channel_count as usize;
+        let window_len = self.duration_to_frames(window).max(1) * channel_count;
+
+        self.samples.chunks(window_len).map(move |window| {
+            let mut peaks = vec![0; channel_count];
+            for channel_samples in window.chunks(channel_count) {
+                for (peak, &sample) in peaks.iter_mut().zip(channel_samples) {
+                    *peak = sample.saturating_abs().max(*peak);
+                }
+            }
+            peaks
+        })
+    }
+
+    /// Split the song into consecutive non-looping clips that are each `len`
+    /// long. The last clip may be shorter. Every clip holds the same number of
+    /// samples for each audio channel.
+    pub fn segment(&self, len: Duration) -> Vec<DecodedHps> {
+        let segment_len = self.duration_to_frames(len).max(1) * self.channel_count as usize;
+        self.samples
+            .chunks(segment_len)
+            .map(|chunk| self.with_samples(chunk.to_vec()))
+            .collect()
+    }
+
+    /// Smooth over the seam where a looping song jumps back to its loop point,
+    /// by blending the last `len` of the song into the `len` just before the
+    /// loop point. By the end of the song, the audio matches what leads into
+    /// the loop point, so jumping back to it doesn't click.
+    ///
+    /// Each audio channel is blended separately. The crossfade is shortened to
+    /// fit within both the intro and the loop section, so songs that loop from
+    /// the very beginning, or don't loop at all, are returned unchanged.
+    pub fn with_loop_crossfade(mut self, len: Duration) -> DecodedHps {
+        let Some(loop_sample_index) = self.loop_sample_index else {
+            return self;
+        };
+        let channel_count = self.channel_count as usize;
+        let fade_len = self
+            .duration_to_frames(len)
+            .min(loop_sample_index / channel_count)
+            .min((self.samples.len() - loop_sample_index) / channel_count)
+            * channel_count;
+        if fade_len == 0 {
+            return self;
+        }
+
+        // Linearly fade out the tail of the loop while fading in the audio
+        // that precedes the loop point
+        let tail_start = self.samples.len() - fade_len;
+        let fade_frames = (fade_len / channel_count) as i64;
+        for i in 0..fade_len {
+            let position = (i / channel_count) as i64;
+            let out = self.samples[tail_start + i] as i64;
+            let inc = self.samples[loop_sample_index - fade_len + i] as i64;
+            self.samples[tail_start + i] =
+                ((out * (fade_frames - position) + inc * position) / fade_frames) as i16;
+        }
+        self
+    }
+
+    /// Combine this song with `next` into a single non-looping song, blending
+    /// the last `len` of this song into the first `len` of `next`.
+    ///
+    /// If either song is shorter than `len`, the crossfade is shortened to
+    /// fit. Both songs must have the same sample rate and channel count.
+    pub fn crossfade_into(&self, next: &DecodedHps, len: Duration) -> Result<DecodedHps, MixError> {
+        if self.sample_rate != next.sample_rate {
+            return Err(MixError::SampleRateMismatch(
+                self.sample_rate,
+                next.sample_rate,
+            ));
+        }
+        if self.channel_count != next.channel_count {
+            return Err(MixError::ChannelCountMismatch(
+                self.channel_count,
+                next.channel_count,
+            ));
+        }
+
+        let channel_count = self.channel_count as usize;
+        let fade_len = self
+            .duration_to_frames(len)
+            .min(self.samples.len() / channel_count)
+            .min(next.samples.len() / channel_count)
+            * channel_count;
+
+        let (head, tail) = self.samples.split_at(self.samples.len() - fade_len);
+        let (intro, rest) = next.samples.split_at(fade_len);
+
+        // Linearly fade out the tail of this song while fading in the intro of
+        // the next one
+        let blended = tail.iter().zip(intro).enumerate().map(|(i, (&out, &inc))| {
+            let position = (i / channel_count) as i64;
+            let fade_frames = (fade_len / channel_count) as i64;
+            ((out as i64 * (fade_frames - position) + inc as i64 * position) / fade_frames) as i16
+        });
+
+        let samples = head
+            .iter()
+            .copied()
+            .chain(blended)
+            .chain(rest.iter().copied())
+            .collect();
+        Ok(self.with_samples(samples))
     }
 }
 
+/// Convert an `i16` sample to an `f32` in the range `[-1.0, 1.0]`.
+///
+/// This divides by `32768` rather than `i16::MAX`, so that `i16::MIN` maps to
+/// exactly `-1.0` instead of slightly below it.
+#[inline(always)]
+fn sample_to_f32(sample: i16) -> f32 {
+    sample as f32 / 32768.0
+}
+
+/// A second order IIR filter, used for K-weighting
+#[cfg(feature = "std")]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    /// The previous two inputs and outputs
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+#[cfg(feature = "std")]
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    /// The high shelf stage of the BS.1770 K-weighting filter, which models the
+    /// acoustic effect of the head
+    fn k_weighting_shelf(sample_rate: f64) -> Self {
+        let k = (core::f64::consts::PI * 1681.974450955533 / sample_rate).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+
+    /// The high pass stage of the BS.1770 K-weighting filter
+    fn k_weighting_high_pass(sample_rate: f64) -> Self {
+        let k = (core::f64::consts::PI * 38.13547087602444 / sample_rate).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// A tiny pseudo-random number generator, used for dithering
+struct XorShift32(u32);
+
+impl XorShift32 {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// A [`DecodedHps`] that stops after a fixed number of samples, optionally
+/// fading out at the end. Unlike a looping [`DecodedHps`], this always ends.
+///
+/// Created by [`DecodedHps::loop_then_fade_out`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiniteLoop {
+    inner: DecodedHps,
+    /// Total number of samples that will be played
+    total_len: usize,
+    /// Number of samples left to play
+    remaining: usize,
+    /// Number of samples at the end that are faded out
+    fade_len: usize,
+}
+
+impl FiniteLoop {
+    fn new(mut inner: DecodedHps, total_len: usize, fade_len: usize) -> Self {
+        inner.current_index = 0;
+        inner.back_index = 0;
+        Self {
+            inner,
+            total_len,
+            remaining: total_len,
+            fade_len,
+        }
+    }
+}
+
+impl Iterator for FiniteLoop {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let sample = self.inner.next()?;
+
+        // Scale every channel of a sample by the same amount, so the fade
+        // doesn't pan from one channel to the other
+        let channel_count = self.inner.channel_count as usize;
+        let remaining_frames = self.remaining.div_ceil(channel_count) as i32;
+        let fade_frames = (self.fade_len / channel_count) as i32;
+        self.remaining -= 1;
+
+        if remaining_frames <= fade_frames {
+            Some((sample as i32 * remaining_frames / fade_frames) as i16)
+        } else {
+            Some(sample)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for FiniteLoop {}
+
 #[cfg(feature = "rodio-source")]
-impl rodio::Source for DecodedHps {
+impl rodio::Source for FiniteLoop {
     fn current_frame_len(&self) -> Option<usize> {
         None
     }
+    fn channels(&self) -> u16 {
+        self.inner.channel_count as u16
+    }
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate
+    }
+    fn total_duration(&self) -> Option<core::time::Duration> {
+        Some(self.inner.sample_index_to_duration(self.total_len))
+    }
+}
+
+#[cfg(feature = "rodio-source")]
+impl rodio::Source for DecodedHps {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.samples_until_span_end())
+    }
     fn channels(&self) -> u16 {
         self.channel_count as u16
     }
     fn sample_rate(&self) -> u32 {
         self.sample_rate
     }
-    fn total_duration(&self) -> Option<std::time::Duration> {
+    fn total_duration(&self) -> Option<core::time::Duration> {
         if self.is_looping() {
             None
         } else {
@@ -110,3 +1262,810 @@ impl rodio::Source for DecodedHps {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse one of the files in `test-data`
+    fn read_test_file(name: &str) -> Hps {
+        std::fs::read(format!("test-data/{name}"))
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    /// Parse and decode one of the files in `test-data`
+    fn decode_test_file(name: &str) -> DecodedHps {
+        read_test_file(name).decode().unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn measures_integrated_loudness() {
+        // A full scale 997 Hz sine in both channels measures 0 LUFS
+        let samples = (0..48_000 * 3)
+            .map(|n| {
+                let phase = n as f64 * 997.0 / 48_000.0 * std::f64::consts::TAU;
+                (phase.sin() * 32767.0) as i16
+            })
+            .flat_map(|sample| [sample, sample])
+            .collect::<Vec<_>>();
+        let tone = DecodedHps::from_samples(samples, 48_000, 2);
+        assert!(tone.integrated_loudness().abs() < 0.1);
+
+        let silence = DecodedHps::from_samples(vec![0; 48_000 * 2], 48_000, 2);
+        assert_eq!(silence.integrated_loudness(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    #[cfg(feature = "wav")]
+    fn writes_wav_files() {
+        let audio = DecodedHps::from_samples(vec![1, -1, 2, -2, i16::MAX, i16::MIN], 32_000, 2);
+        let mut wav = std::io::Cursor::new(Vec::new());
+        audio.write_wav(&mut wav).unwrap();
+        let wav = wav.into_inner();
+
+        assert_eq!(wav.len(), 44 + 12);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 48);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes(wav[22..24].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 32_000);
+        assert_eq!(u32::from_le_bytes(wav[28..32].try_into().unwrap()), 128_000);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 12);
+        assert_eq!(&wav[44..48], [1, 0, 0xFF, 0xFF]);
+        assert_eq!(&wav[54..], [0x00, 0x80]);
+
+        // The byte rate doesn't fit in the header
+        let audio = DecodedHps::from_samples(vec![0; 2], u32::MAX, 2);
+        let error = audio
+            .write_wav(std::io::Cursor::new(Vec::new()))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn seeks_within_looping_songs() {
+        let mut decoded = decode_test_file("short-last-block-with-loop.hps");
+        let frame_count = decoded.samples().len() / 2;
+
+        assert_eq!(decoded.seek(Duration::from_secs(1)), Duration::from_secs(1));
+        assert_eq!(decoded.next(), Some(decoded.samples()[64_000]));
+
+        // Seeking past the end wraps around to the loop
+        assert_eq!(decoded.seek_to_sample(frame_count + 5), 95_984 + 5);
+        assert_eq!(decoded.next(), Some(decoded.samples()[(95_984 + 5) * 2]));
+    }
+
+    #[test]
+    fn seeking_restores_samples_taken_from_the_back() {
+        let mut decoded = DecodedHps::from_samples((0..20).collect(), 32_000, 2);
+        assert_eq!(decoded.next_back(), Some(18));
+        assert_eq!(decoded.next_back(), Some(19));
+        assert_eq!(decoded.size_hint(), (18, Some(18)));
+
+        assert_eq!(decoded.seek_to_sample(5), 5);
+        assert_eq!(decoded.size_hint(), (10, Some(10)));
+        assert!(decoded.eq(10..20));
+    }
+
+    #[test]
+    fn toggles_looping_after_decoding() {
+        let hps = read_test_file("short-last-block-with-loop.hps");
+        let mut decoded = hps.decode().unwrap();
+        let sample_count = decoded.samples().len();
+
+        decoded.set_looping(false);
+        assert!(!decoded.is_looping());
+        assert_eq!(decoded.clone().count(), sample_count);
+
+        decoded.set_looping(true);
+        assert!(decoded.is_looping());
+        assert_eq!(decoded, hps.decode().unwrap());
+    }
+
+    #[test]
+    fn reports_exact_size_hints() {
+        let mut decoded = decode_test_file("short-last-block-with-loop.hps");
+        let sample_count = decoded.samples().len();
+        assert_eq!(decoded.size_hint(), (usize::MAX, None));
+
+        decoded.set_looping(false);
+        decoded.nth(99);
+        assert_eq!(
+            decoded.size_hint(),
+            (sample_count - 100, Some(sample_count - 100))
+        );
+        assert_eq!(decoded.clone().count(), sample_count - 100);
+
+        decoded.set_looping(true);
+        let finite = decoded.loop_then_fade_out(2, Duration::ZERO);
+        assert!(finite.len() > sample_count);
+        assert_eq!(finite.len(), finite.count());
+    }
+
+    #[test]
+    fn loops_a_fixed_number_of_times_then_fades_out() {
+        let decoded = decode_test_file("short-last-block-with-loop.hps");
+        let samples = decoded.samples().to_vec();
+        let loop_sample_index = 95_984 * 2;
+        let loop_section = &samples[loop_sample_index..];
+        let fade_frames = decoded.sample_rate as usize / 2;
+
+        // The song plays once, then the loop section twice more, then the
+        // start of a third loop fades out
+        let finite = decoded
+            .clone()
+            .loop_then_fade_out(2, Duration::from_millis(500));
+        let expected_len = samples.len() + 2 * loop_section.len() + fade_frames * 2;
+        assert_eq!(finite.len(), expected_len);
+        let played: Vec<i16> = finite.collect();
+        assert_eq!(played.len(), expected_len);
+        assert_eq!(played[..samples.len()], samples[..]);
+        let looped = &played[samples.len()..];
+        assert_eq!(looped[..loop_section.len()], loop_section[..]);
+        assert_eq!(
+            looped[loop_section.len()..loop_section.len() * 2],
+            loop_section[..]
+        );
+        let faded = &looped[loop_section.len() * 2..];
+        let unfaded = &loop_section[..fade_frames * 2];
+        for (frame, (faded, unfaded)) in faded
+            .chunks_exact(2)
+            .zip(unfaded.chunks_exact(2))
+            .enumerate()
+        {
+            let gain = (fade_frames - frame) as i32;
+            for (&faded, &unfaded) in faded.iter().zip(unfaded) {
+                assert_eq!(faded as i32, unfaded as i32 * gain / fade_frames as i32);
+            }
+        }
+
+        // Without a loop, the song plays once and its own tail fades out
+        let mut once = decoded;
+        once.set_looping(false);
+        let finite = once.loop_then_fade_out(5, Duration::from_millis(500));
+        assert_eq!(finite.len(), samples.len());
+        let played: Vec<i16> = finite.collect();
+        assert_eq!(played.len(), samples.len());
+        let fade_start = samples.len() - fade_frames * 2;
+        assert_eq!(played[..fade_start], samples[..fade_start]);
+        assert_eq!(
+            played[fade_start..fade_start + 2],
+            samples[fade_start..fade_start + 2]
+        );
+        assert_eq!(
+            played[samples.len() - 2..],
+            samples[samples.len() - 2..]
+                .iter()
+                .map(|&sample| (sample as i32 / fade_frames as i32) as i16)
+                .collect::<Vec<_>>()[..]
+        );
+    }
+
+    #[test]
+    fn loops_without_skipping_or_repeating_samples() {
+        let mut decoded = decode_test_file("short-last-block-with-loop.hps");
+        let samples = decoded.samples().to_vec();
+        let loop_sample_index = 95_984 * 2;
+
+        // The intro span runs to the end of the song
+        assert_eq!(decoded.samples_until_span_end(), samples.len());
+        decoded.nth(samples.len() - 3);
+        assert_eq!(decoded.samples_until_span_end(), 2);
+
+        // Right after the last sample, playback continues from the loop point
+        let boundary = decoded.by_ref().take(6).collect::<Vec<_>>();
+        assert_eq!(boundary[..2], samples[samples.len() - 2..]);
+        assert_eq!(
+            boundary[2..],
+            samples[loop_sample_index..loop_sample_index + 4]
+        );
+        assert_eq!(
+            decoded.samples_until_span_end(),
+            samples.len() - loop_sample_index - 4
+        );
+
+        // Every later span is exactly the loop section
+        decoded.nth(decoded.samples_until_span_end() - 1);
+        assert_eq!(
+            decoded.samples_until_span_end(),
+            samples.len() - loop_sample_index
+        );
+    }
+
+    #[test]
+    fn finishes_output_one_buffer_after_the_last_sample() {
+        let fill = |audio: &mut DecodedHps| {
+            let mut data = [0i32; 4];
+            let finished = audio.fill_output_buffer(&mut data, i32::from, -1);
+            (data, finished)
+        };
+
+        // The song ends partway through the third buffer, which still has to
+        // be played, so only the fourth reports that playback is finished
+        let mut audio = DecodedHps::from_samples((1..=10).collect(), 32_000, 2);
+        assert_eq!(fill(&mut audio), ([1, 2, 3, 4], false));
+        assert_eq!(fill(&mut audio), ([5, 6, 7, 8], false));
+        assert_eq!(fill(&mut audio), ([9, 10, -1, -1], false));
+        assert_eq!(fill(&mut audio), ([-1, -1, -1, -1], true));
+
+        // The same goes for a song that ends exactly at the end of a buffer
+        let mut audio = DecodedHps::from_samples((1..=8).collect(), 32_000, 2);
+        assert_eq!(fill(&mut audio), ([1, 2, 3, 4], false));
+        assert_eq!(fill(&mut audio), ([5, 6, 7, 8], false));
+        assert_eq!(fill(&mut audio), ([-1, -1, -1, -1], true));
+
+        // Looping songs never finish
+        let mut audio = decode_test_file("short-last-block-with-loop.hps");
+        let buffers = audio.samples().len() / 4 * 2;
+        assert!((0..buffers).all(|_| !fill(&mut audio).1));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn skips_ahead_like_repeated_next_calls() {
+        let samples = (0..200).map(|n| n as i16).collect::<Vec<_>>();
+        let hps = Hps::from_pcm(&samples, 32_000, 1, Some(70)).unwrap();
+        let looping = hps.decode().unwrap();
+        let mut non_looping = looping.clone();
+        non_looping.set_looping(false);
+
+        let naive_nth = |audio: &mut DecodedHps, n: usize| {
+            for _ in 0..n {
+                audio.next();
+            }
+            audio.next()
+        };
+        for mut audio in [looping, non_looping] {
+            let mut expected = audio.clone();
+            for n in [0, 5, 150, 70, 400, 1_000, 3] {
+                assert_eq!(audio.nth(n), naive_nth(&mut expected, n));
+                assert_eq!(audio, expected);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn splits_intro_from_loop() {
+        let samples = (0..200).map(|n| n as i16).collect::<Vec<_>>();
+        let mut decoded = Hps::from_pcm(&samples, 32_000, 1, Some(70))
+            .unwrap()
+            .decode()
+            .unwrap();
+        let loop_start = decoded.loop_start_time();
+        assert_eq!(decoded.intro_samples().len(), 70);
+        assert_eq!(
+            [decoded.intro_samples(), decoded.loop_samples().unwrap()].concat(),
+            decoded.samples()
+        );
+
+        decoded.skip_to_loop();
+        assert_eq!(Some(decoded.position()), loop_start);
+        assert_eq!(decoded.next(), Some(decoded.loop_samples().unwrap()[0]));
+
+        decoded.set_looping(false);
+        assert_eq!(decoded.intro_samples(), decoded.samples());
+        assert_eq!(decoded.loop_samples(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn crossfades_the_loop_seam() {
+        // Ramps that jump from 209 back down to 70 when the song loops
+        let samples = (0..210)
+            .flat_map(|n| [n * 100, n * -100])
+            .collect::<Vec<i16>>();
+        let looping = Hps::from_pcm(&samples, 32_000, 2, Some(70))
+            .unwrap()
+            .decode()
+            .unwrap();
+        let original = looping.samples().to_vec();
+        let loop_start = 70 * 2;
+
+        // 20 frames
+        let smoothed = looping
+            .clone()
+            .with_loop_crossfade(Duration::from_micros(625));
+        let seam = smoothed.samples();
+        assert_eq!(seam[..380], original[..380]);
+        for channel in 0..2 {
+            let out = original[418 + channel] as i64;
+            let inc = original[loop_start - 2 + channel] as i64;
+            assert_eq!(seam[418 + channel] as i64, (out + inc * 19) / 20);
+
+            let jump =
+                |samples: &[i16]| (samples[418 + channel] - samples[loop_start + channel]).abs();
+            assert!(jump(seam) < jump(&original) / 10);
+        }
+
+        // The crossfade can't be longer than the intro, and songs that don't
+        // loop are left alone
+        let clamped = looping.clone().with_loop_crossfade(Duration::from_secs(1));
+        assert_eq!(clamped.samples()[..280], original[..280]);
+        let mut non_looping = looping;
+        non_looping.set_looping(false);
+        assert_eq!(
+            non_looping
+                .clone()
+                .with_loop_crossfade(Duration::from_secs(1)),
+            non_looping
+        );
+    }
+
+    #[test]
+    fn crossfades_into_another_song() {
+        let frames = |count: usize, left: i16, right: i16| {
+            DecodedHps::from_samples([left, right].repeat(count), 10, 2)
+        };
+        let outgoing = frames(10, 1000, -1000);
+        let incoming = frames(6, 0, 2000);
+
+        // 400ms is 4 frames at 10 Hz, blended linearly from all of the
+        // outgoing song towards all of the incoming one
+        let mixed = outgoing
+            .crossfade_into(&incoming, Duration::from_millis(400))
+            .unwrap();
+        assert_eq!(mixed.samples().len(), (10 + 6 - 4) * 2);
+        assert!(!mixed.is_looping());
+        assert_eq!(mixed.samples()[..6 * 2], outgoing.samples()[..6 * 2]);
+        assert_eq!(
+            mixed.samples()[6 * 2..10 * 2],
+            [1000, -1000, 750, -250, 500, 500, 250, 1250]
+        );
+        assert_eq!(mixed.samples()[10 * 2..], incoming.samples()[4 * 2..]);
+
+        // The fade is shortened to fit the shorter song
+        let clamped = outgoing
+            .crossfade_into(&incoming, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(clamped.samples().len(), 10 * 2);
+        assert_eq!(clamped.samples()[..4 * 2], outgoing.samples()[..4 * 2]);
+        assert_eq!(clamped.samples()[4 * 2..5 * 2], [1000, -1000]);
+
+        let joined = outgoing.crossfade_into(&incoming, Duration::ZERO).unwrap();
+        assert_eq!(
+            joined.samples(),
+            [outgoing.samples(), incoming.samples()].concat()
+        );
+
+        assert!(matches!(
+            outgoing.crossfade_into(&DecodedHps::from_samples(vec![0; 6], 10, 3), Duration::ZERO),
+            Err(MixError::ChannelCountMismatch(2, 3))
+        ));
+        assert!(matches!(
+            outgoing.crossfade_into(&DecodedHps::from_samples(vec![0; 6], 20, 2), Duration::ZERO),
+            Err(MixError::SampleRateMismatch(10, 20))
+        ));
+    }
+
+    #[test]
+    fn repeats_the_loop_section_exactly() {
+        let mut decoded = decode_test_file("short-last-block-with-loop.hps");
+        let samples = decoded.samples().to_vec();
+        let loop_section = &samples[95_984 * 2..];
+
+        let intro_and_two_loops = decoded
+            .by_ref()
+            .take(samples.len() + loop_section.len() * 2)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            intro_and_two_loops,
+            [&samples[..], loop_section, loop_section].concat()
+        );
+        assert_eq!(decoded.next(), Some(loop_section[0]));
+    }
+
+    #[test]
+    fn plays_non_looping_songs_backwards() {
+        let mut decoded = decode_test_file("short-last-block-with-loop.hps");
+        assert_eq!(decoded.next_back(), None);
+
+        decoded.set_looping(false);
+        let expected = decoded
+            .samples()
+            .chunks(2)
+            .rev()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(decoded.clone().rev().collect::<Vec<_>>(), expected);
+
+        // Both ends stop where they meet
+        let front = decoded.by_ref().take(10).collect::<Vec<_>>();
+        let back = decoded.by_ref().rev().collect::<Vec<_>>();
+        assert_eq!(front, decoded.samples()[..10]);
+        assert_eq!(back, expected[..expected.len() - 10]);
+        assert_eq!(decoded.next(), None);
+    }
+
+    #[test]
+    fn splits_samples_by_channel() {
+        let stereo = DecodedHps::from_samples(vec![1, -1, 2, -2, 3, -3], 32_000, 2);
+        assert_eq!(stereo.channel_samples(0), Some(vec![1, 2, 3]));
+        assert_eq!(stereo.channel_samples(1), Some(vec![-1, -2, -3]));
+        assert_eq!(stereo.channel_samples(2), None);
+        assert_eq!(
+            stereo.planar_samples(),
+            vec![vec![1, 2, 3], vec![-1, -2, -3]]
+        );
+
+        // Mono samples are passed through as they are
+        let mono = DecodedHps::from_samples(vec![1, -1, 2], 32_000, 1);
+        assert_eq!(mono.channel_samples(0).as_deref(), Some(mono.samples()));
+        assert_eq!(mono.channel_samples(1), None);
+        assert_eq!(mono.planar_samples(), vec![mono.samples().to_vec()]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn converts_samples_to_f32_without_clipping() {
+        let decoded = DecodedHps::from_samples(vec![0, 16_384, i16::MAX, i16::MIN], 32_000, 2);
+        let samples = decoded.samples_f32();
+        assert_eq!(samples[..2], [0.0, 0.5]);
+        assert!(samples[2] < 1.0 && samples[2] > 0.9999);
+        assert_eq!(samples[3], -1.0);
+
+        let decoded = crate::from_path("test-data/test-song.hps").unwrap();
+        assert!(decoded
+            .samples_f32()
+            .iter()
+            .all(|s| (-1.0..=1.0).contains(s)));
+    }
+
+    #[test]
+    #[cfg(feature = "rodio-source")]
+    fn rodio_samples_stay_within_range() {
+        let decoded = DecodedHps::from_samples(vec![i16::MIN, i16::MAX], 32_000, 2);
+        let samples = decoded.into_samples_buffer().collect::<Vec<f32>>();
+        assert_eq!(samples[0], -1.0);
+        assert!(samples[1] <= 1.0);
+    }
+
+    #[test]
+    #[cfg(feature = "rodio-source")]
+    fn plays_a_bounded_number_of_loops_through_rodio() {
+        use rodio::Source;
+
+        let decoded = decode_test_file("short-last-block-with-loop.hps");
+        let intro_and_loop = decoded.samples().len();
+        let loop_section = (384_048 - 95_984) * 2;
+
+        let looped = decoded.clone().into_rodio_source_looped(3);
+        // 384,048 + 3 * 288,064 samples per channel at 32kHz
+        assert_eq!(
+            looped.total_duration(),
+            Some(Duration::from_micros(39_007_500))
+        );
+        assert_eq!(looped.len(), intro_and_loop + loop_section * 3);
+        assert_eq!(looped.count(), intro_and_loop + loop_section * 3);
+
+        // No loops plays the song through once
+        let once = decoded.clone().into_rodio_source_looped(0);
+        assert_eq!(
+            once.total_duration(),
+            Some(Duration::from_micros(12_001_500))
+        );
+        assert!(once.eq(decoded.samples().iter().copied()));
+    }
+
+    #[test]
+    fn converts_to_u8_pcm() {
+        let samples = vec![i16::MIN, -129, -128, -1, 0, 127, 128, i16::MAX];
+        let decoded = DecodedHps::from_samples(samples.clone(), 32_000, 2);
+        assert_eq!(
+            decoded.to_u8_pcm(false),
+            vec![0, 127, 128, 128, 128, 128, 129, 255]
+        );
+
+        // Rounding to the nearest step means there's no DC offset on average
+        let ramp = DecodedHps::from_samples((-16_384..16_384).collect(), 32_000, 2);
+        let sum = ramp
+            .to_u8_pcm(false)
+            .iter()
+            .map(|&s| s as i64 - 128)
+            .sum::<i64>();
+        assert_eq!(sum, 0);
+
+        // Dither never moves a sample by more than one step
+        let ramp = DecodedHps::from_samples((i16::MIN..=i16::MAX).collect(), 32_000, 2);
+        let exact = ramp.to_u8_pcm(false);
+        let dithered = ramp.to_u8_pcm(true);
+        assert_ne!(dithered, exact);
+        assert!(exact
+            .iter()
+            .zip(&dithered)
+            .all(|(&e, &d)| e.abs_diff(d) <= 1));
+        assert_eq!(dithered, ramp.to_u8_pcm(true));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn applies_gain() {
+        let decoded = DecodedHps::from_samples(vec![0, 100, -100, 20_000, i16::MIN], 32_000, 1);
+        assert_eq!(decoded.peak_amplitude(), i16::MAX);
+        assert_eq!(
+            DecodedHps::from_samples(vec![300, -300, -300, 300], 32_000, 2).rms_amplitude(),
+            300.0
+        );
+
+        let mut unchanged = decoded.clone();
+        unchanged.apply_gain(1.0);
+        assert_eq!(unchanged, decoded);
+
+        let mut inverted = decoded.clone();
+        inverted.apply_gain(-1.0);
+        assert_eq!(inverted.samples(), &[0, -100, 100, -20_000, i16::MAX]);
+
+        let mut boosted = decoded.clone();
+        boosted.apply_gain(2.0);
+        assert_eq!(boosted.samples(), &[0, 200, -200, i16::MAX, i16::MIN]);
+        assert_eq!(boosted.peak_amplitude(), i16::MAX);
+    }
+
+    #[test]
+    fn applies_channel_gains() {
+        let mut audio =
+            DecodedHps::from_samples(vec![100, 100, -200, -200, 30_000, 30_000], 32_000, 2);
+        audio.set_channel_gains(&[0.5, 1.5]).unwrap();
+        assert_eq!(audio.samples(), &[50, 150, -100, -300, 15_000, i16::MAX]);
+
+        let mut mono = DecodedHps::from_samples(vec![100, -100], 32_000, 1);
+        mono.set_channel_gains(&[-1.0]).unwrap();
+        assert_eq!(mono.samples(), &[-100, 100]);
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_channel_gains() {
+        let original = DecodedHps::from_samples(vec![100, 100], 32_000, 2);
+        for gains in [&[][..], &[2.0], &[2.0, 2.0, 2.0]] {
+            let mut audio = original.clone();
+            assert!(matches!(
+                audio.set_channel_gains(gains),
+                Err(MixError::WrongChannelValueCount(2, count)) if count == gains.len()
+            ));
+            assert_eq!(audio, original);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn measures_peak_and_rms_amplitude() {
+        let decoded = decode_test_file("test-song.hps");
+
+        // Both channels clip at i16::MIN somewhere in the song
+        assert_eq!(decoded.peak_per_channel(), vec![i16::MAX, i16::MAX]);
+        assert_eq!(decoded.peak_amplitude(), i16::MAX);
+        assert!((decoded.rms_amplitude() - 8_002.358).abs() < 0.01);
+    }
+
+    #[test]
+    fn computes_waveform_peaks() {
+        let hps = read_test_file("short-last-block-with-loop.hps");
+        let decoded = hps.decode().unwrap();
+        assert_eq!(decoded.waveform_peaks(300), hps.thumbnail(300).unwrap());
+        assert!(decoded.waveform_peaks(0).is_empty());
+
+        let decoded = DecodedHps::from_samples(vec![1, -2, 3, 4], 32_000, 2);
+        assert_eq!(
+            decoded.waveform_peaks(4),
+            vec![(-2, 1), (0, 0), (3, 4), (0, 0)]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "resample")]
+    fn resamples_each_channel() {
+        let decoded = decode_test_file("short-last-block-with-loop.hps");
+        assert_eq!(decoded.resample(32_000), decoded);
+
+        let resampled = decoded.resample(48_000);
+        assert_eq!(resampled.sample_rate, 48_000);
+        assert_eq!(resampled.samples().len(), 384_048 * 3 / 2 * 2);
+        assert_eq!(resampled.loop_start_time(), decoded.loop_start_time());
+
+        // Interpolated samples fall between their neighbours, and the channels
+        // don't bleed into each other
+        let decoded = DecodedHps::from_samples(vec![0, 1000, 30, 1000], 1, 2);
+        assert_eq!(
+            decoded.resample(2).samples(),
+            &[0, 1000, 15, 1000, 30, 1000, 30, 1000]
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "resample", feature = "std"))]
+    fn keeps_resampled_loop_point_within_the_song() {
+        // Loops from the start of the last frame, which rounds to the end of
+        // the song when heavily downsampled
+        let samples = (0..200).map(|n| n as i16).collect::<Vec<_>>();
+        let decoded = Hps::from_pcm(&samples, 32_000, 1, Some(196))
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!(decoded.intro_samples().len(), 196);
+
+        let mut resampled = decoded.resample(100);
+        assert_eq!(resampled.samples().len(), 1);
+        assert_eq!(resampled.loop_samples().unwrap().len(), 1);
+        assert!(resampled.by_ref().take(5).eq([0; 5]));
+    }
+
+    #[test]
+    fn applies_fades() {
+        let original = decode_test_file("short-last-block-with-loop.hps");
+        let samples = original.samples();
+        let fade_frames = 32_000;
+
+        let mut faded = original.clone();
+        faded.apply_fade_in(Duration::from_secs(1));
+        faded.apply_fade_out(Duration::from_secs(1));
+        let faded_samples = faded.samples();
+        let end = samples.len();
+
+        // Both channels of a frame are scaled by the same amount
+        assert_eq!(&faded_samples[..2], &[0, 0]);
+        let middle = fade_frames / 2 * 2;
+        assert_eq!(faded_samples[middle], samples[middle] / 2);
+        assert_eq!(faded_samples[middle + 1], samples[middle + 1] / 2);
+        assert_eq!(
+            &faded_samples[fade_frames * 2..end - fade_frames * 2],
+            &samples[fade_frames * 2..end - fade_frames * 2]
+        );
+        let fade_out_start = end - fade_frames * 2;
+        assert_eq!(
+            faded_samples[fade_out_start..fade_out_start + 2],
+            samples[fade_out_start..fade_out_start + 2]
+        );
+        assert_eq!(faded_samples[end - 2..], [0, 0]);
+
+        // The fade out steps evenly down to silence on the last frame
+        let mut faded = DecodedHps::from_samples(vec![900; 10 * 2], 10, 2);
+        faded.apply_fade_out(Duration::from_secs(1));
+        let expected = (0..10)
+            .rev()
+            .flat_map(|step| [step * 100, step * 100])
+            .collect::<Vec<_>>();
+        assert_eq!(faded.samples(), expected);
+
+        let mut faded = DecodedHps::from_samples(vec![900; 2 * 2], 10, 2);
+        faded.apply_fade_out(Duration::from_millis(100));
+        assert_eq!(faded.samples(), [900, 900, 0, 0]);
+
+        // Fades longer than the song cover the whole song
+        let mut faded = original.clone();
+        faded.apply_fade_out(Duration::from_secs(100));
+        assert_eq!(faded.samples()[..2], samples[..2]);
+        assert_eq!(faded.samples().len(), samples.len());
+    }
+
+    #[test]
+    fn computes_looped_durations() {
+        let mut hps = read_test_file("short-last-block-with-loop.hps");
+        let decoded = hps.decode().unwrap();
+        let loop_section = Duration::from_millis(9_002);
+        assert_eq!(decoded.loop_section_duration(), Some(loop_section));
+        assert_eq!(decoded.looped_duration(0), decoded.duration());
+        assert_eq!(
+            decoded.looped_duration(3),
+            decoded.duration() + loop_section * 3
+        );
+
+        hps.loop_block_index = None;
+        let decoded = hps.decode().unwrap();
+        assert_eq!(decoded.loop_section_duration(), None);
+        assert_eq!(decoded.looped_duration(3), decoded.duration());
+    }
+
+    #[test]
+    fn content_hash_ignores_block_layout() {
+        let hps = read_test_file("short-last-block-with-loop.hps");
+        let mut decoded = hps.decode().unwrap();
+        assert_eq!(decoded.content_hash(), 0x0f5e_a2d0_74cd_08b5);
+
+        let mut coalesced = hps.clone();
+        coalesced.coalesce_blocks(usize::MAX).unwrap();
+        assert_ne!(coalesced.structure_hash(), hps.structure_hash());
+        assert_eq!(
+            coalesced.decode().unwrap().content_hash(),
+            decoded.content_hash()
+        );
+
+        let hash = decoded.content_hash();
+        decoded.nth(1_000);
+        assert_eq!(decoded.content_hash(), hash);
+
+        let mut audio = DecodedHps::from_samples(vec![1, 2, 3, 4], 32_000, 2);
+        let hash = audio.content_hash();
+        audio.apply_gain(0.5);
+        assert_ne!(audio.content_hash(), hash);
+    }
+
+    #[test]
+    fn estimates_memory_usage() {
+        let empty = DecodedHps::from_samples(Vec::new(), 32_000, 2);
+        let short = DecodedHps::from_samples(vec![0; 1_000], 32_000, 2);
+        assert_eq!(empty.memory_bytes(), core::mem::size_of::<DecodedHps>());
+        assert_eq!(short.memory_bytes() - empty.memory_bytes(), 1_000 * 2);
+
+        let decoded = decode_test_file("short-last-block-with-loop.hps");
+        assert!(decoded.memory_bytes() >= decoded.samples().len() * 2);
+    }
+
+    #[test]
+    fn splits_into_segments() {
+        let decoded = decode_test_file("short-last-block-with-loop.hps");
+        let segments = decoded.segment(Duration::from_secs(1));
+
+        // 384,048 samples per channel make 12 whole seconds at 32kHz, plus 48
+        assert_eq!(segments.len(), 13);
+        for segment in &segments[..12] {
+            assert_eq!(segment.samples().len(), 32_000 * 2);
+            assert_eq!(segment.loop_sample_index, None);
+            assert_eq!(segment.sample_rate, 32_000);
+        }
+        assert_eq!(segments[12].samples().len(), 48 * 2);
+
+        let joined = segments
+            .iter()
+            .flat_map(|segment| segment.samples())
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(joined, decoded.samples());
+    }
+
+    #[test]
+    fn meters_the_peak_of_each_window() {
+        // At 4 Hz, one second windows hold 4 samples per channel, and the
+        // last window holds the 2 that are left over
+        let left = [1, -2, 3, 0, 5, 6, -7, 8, 9, -10];
+        let right = [0, 0, 0, 0, 0, 0, 0, 0, 0, i16::MIN];
+        let samples = left.into_iter().zip(right).flat_map(|(l, r)| [l, r]);
+        let decoded = DecodedHps::from_samples(samples.collect(), 4, 2);
+
+        let peaks = decoded
+            .meter_frames(Duration::from_secs(1))
+            .collect::<Vec<_>>();
+        assert_eq!(peaks, [vec![3, 0], vec![8, 0], vec![10, i16::MAX]]);
+
+        // Windows shorter than a sample still hold one sample per channel
+        assert_eq!(decoded.meter_frames(Duration::ZERO).count(), 10);
+    }
+
+    #[test]
+    fn detects_dual_mono() {
+        let stereo = decode_test_file("test-song.hps");
+        assert!(!stereo.is_dual_mono(0));
+        assert!(!stereo.is_dual_mono(100));
+
+        let left = stereo.channel_samples(0).unwrap();
+        let samples = left.iter().flat_map(|&sample| [sample, sample]).collect();
+        assert!(DecodedHps::from_samples(samples, 32_000, 2).is_dual_mono(0));
+
+        // This one was encoded from the same audio in both channels
+        assert!(decode_test_file("short-last-block-with-loop.hps").is_dual_mono(0));
+
+        // Channels may differ by up to the tolerance, in either direction
+        let nearly_dual_mono =
+            DecodedHps::from_samples(vec![10, 13, 0, -3, i16::MIN, i16::MIN], 32_000, 2);
+        assert!(nearly_dual_mono.is_dual_mono(3));
+        assert!(!nearly_dual_mono.is_dual_mono(2));
+
+        let mono = DecodedHps::from_samples(left, 32_000, 1);
+        assert!(!mono.is_dual_mono(i16::MAX));
+    }
+
+    #[test]
+    fn measures_duration_in_game_frames() {
+        // 384,048 samples per channel at 32kHz last 12.0015 seconds
+        let decoded = decode_test_file("short-last-block-with-loop.hps");
+        assert!((decoded.duration_in_game_frames(60.0) - 720.09).abs() < 1e-9);
+        assert!((decoded.duration_in_game_frames(30.0) - 360.045).abs() < 1e-9);
+
+        let one_second = DecodedHps::from_samples(vec![0; 32_000 * 2], 32_000, 2);
+        assert_eq!(one_second.duration_in_game_frames(60.0), 60.0);
+    }
+}
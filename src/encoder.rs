@@ -0,0 +1,580 @@
+//! Contains [`encode_frames`] for encoding PCM samples into DSP ADPCM
+//! [`Frame`]s, the inverse of decoding. This is the building block for writing
+//! `.hps` files. To encode a whole song, see [`HpsBuilder`] or [`Hps::from_pcm`].
+//!
+//! ```
+//! let mut decoder_state = DSPDecoderState::default();
+//! let frames = encode_frames(&samples, &hps.channel_info[0].coefficients, &mut decoder_state);
+//! ```
+
+#[cfg(feature = "std")]
+use alloc::vec;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use crate::errors::BuildError;
+#[cfg(any(doc, feature = "std"))]
+use crate::hps::Hps;
+#[cfg(feature = "std")]
+use crate::hps::COEFFICIENT_PAIRS_PER_CHANNEL;
+use crate::hps::{clamp_i16, decode_sample_unclamped, DSPDecoderState, Frame, SAMPLES_PER_FRAME};
+
+/// Encode up to 14 PCM samples into a [`Frame`] using a predetermined frame
+/// header (coefficient index and scale).
+///
+/// Each nibble is chosen so that decoding it reproduces the original sample as
+/// closely as possible. When several nibbles decode to the same (clamped)
+/// sample, the one whose unclamped value is closest to the original wins, which
+/// matches the behaviour of Nintendo's encoder. `hist1` and `hist2` are updated with the samples that
+/// the returned frame will _decode_ to, so that the next frame can be encoded
+/// from the same state the decoder will be in.
+pub(crate) fn encode_frame_with_header(
+    samples: &[i16],
+    header: u8,
+    coefficients: &[(i16, i16)],
+    hist1: &mut i16,
+    hist2: &mut i16,
+) -> Frame {
+    encode_frame_scored(samples, header, coefficients, hist1, hist2).0
+}
+
+/// Encode up to 14 PCM samples into a [`Frame`], choosing the coefficient
+/// pair and scale that reproduce them most accurately. `hist1` and `hist2` are
+/// updated with the samples that the returned frame will decode to.
+pub(crate) fn encode_frame(
+    samples: &[i16],
+    coefficients: &[(i16, i16)],
+    hist1: &mut i16,
+    hist2: &mut i16,
+) -> Frame {
+    let mut best: Option<(u64, Frame, i16, i16)> = None;
+
+    for (coef_index, &(coef1, coef2)) in coefficients.iter().enumerate() {
+        // The estimate is based on the original samples rather than the
+        // decoded ones, so the next scale up is tried too
+        let shift = estimate_scale_shift(samples, coef1, coef2, *hist1, *hist2);
+        for shift in shift..=(shift + 1).min(MAX_SCALE_SHIFT) {
+            let header = ((coef_index as u8) << 4) | shift;
+            let (mut frame_hist1, mut frame_hist2) = (*hist1, *hist2);
+            let (frame, error) = encode_frame_scored(
+                samples,
+                header,
+                coefficients,
+                &mut frame_hist1,
+                &mut frame_hist2,
+            );
+            if best
+                .as_ref()
+                .is_none_or(|(best_error, ..)| error < *best_error)
+            {
+                best = Some((error, frame, frame_hist1, frame_hist2));
+            }
+        }
+    }
+
+    let (_, frame, frame_hist1, frame_hist2) = best.unwrap_or_else(|| unreachable!());
+    *hist1 = frame_hist1;
+    *hist2 = frame_hist2;
+    frame
+}
+
+/// Encode samples from a single audio channel into frames of 14 samples each,
+/// choosing the coefficient pair and scale for every frame that reproduce its
+/// samples most accurately. If the number of samples isn't a multiple of 14,
+/// the last frame is padded out.
+///
+/// `decoder_state` holds the decoder history before the first sample, which
+/// is what a block's [`DSPDecoderState`] should be set to in order to decode
+/// the frames. It's updated to the history after the last frame, exactly as
+/// the decoder will see it (saturation included), so it can be used to encode
+/// the frames of the next block.
+pub fn encode_frames(
+    samples: &[i16],
+    coefficients: &[(i16, i16)],
+    decoder_state: &mut DSPDecoderState,
+) -> Vec<Frame> {
+    let hist1 = &mut decoder_state.initial_hist_1;
+    let hist2 = &mut decoder_state.initial_hist_2;
+    samples
+        .chunks(SAMPLES_PER_FRAME)
+        .map(|frame_samples| encode_frame(frame_samples, coefficients, hist1, hist2))
+        .collect()
+}
+
+/// The largest scale a frame can use, since `8 << 12` already covers the full
+/// range of an `i16`
+const MAX_SCALE_SHIFT: u8 = 12;
+
+/// Estimate the smallest scale (as a power of two) that fits the difference
+/// between each sample and its prediction into a nibble
+fn estimate_scale_shift(samples: &[i16], coef1: i16, coef2: i16, hist1: i16, hist2: i16) -> u8 {
+    let (mut hist1, mut hist2) = (hist1 as i32, hist2 as i32);
+    let mut max_residual = 0;
+    for &sample in samples {
+        let predicted = (coef1 as i32 * hist1 + coef2 as i32 * hist2 + 1024) >> 11;
+        max_residual = max_residual.max((sample as i32 - predicted).abs());
+        hist2 = hist1;
+        hist1 = sample as i32;
+    }
+
+    let mut shift = 0;
+    while shift < MAX_SCALE_SHIFT && 7 << shift < max_residual {
+        shift += 1;
+    }
+    shift
+}
+
+/// Like [`encode_frame_with_header`], but also returns the sum of the squared
+/// differences between the original and decoded samples
+fn encode_frame_scored(
+    samples: &[i16],
+    header: u8,
+    coefficients: &[(i16, i16)],
+    hist1: &mut i16,
+    hist2: &mut i16,
+) -> (Frame, u64) {
+    let scale = 1 << (header & 0xF);
+    let (coef1, coef2) = coefficients[(header >> 4) as usize];
+
+    let mut nibbles = [0u8; SAMPLES_PER_FRAME];
+    let mut squared_error = 0;
+    for (nibble, &sample) in nibbles.iter_mut().zip(samples) {
+        let (best_nibble, decoded) = (-8..=7)
+            .map(|n| {
+                let unclamped = decode_sample_unclamped(n, scale, coef1, coef2, *hist1, *hist2);
+                (n, unclamped)
+            })
+            .min_by_key(|&(_, unclamped)| {
+                let error = (clamp_i16(unclamped) as i32 - sample as i32).abs();
+                let unclamped_error = (unclamped - sample as i32).abs();
+                (error, unclamped_error)
+            })
+            .map(|(n, unclamped)| (n, clamp_i16(unclamped)))
+            .unwrap_or_else(|| unreachable!());
+
+        *nibble = best_nibble as u8 & 0xF;
+        squared_error += (decoded as i64 - sample as i64).pow(2) as u64;
+        *hist2 = *hist1;
+        *hist1 = decoded;
+    }
+
+    // Padding nibbles are left as zero, but still move the decoder history on
+    for _ in samples.len()..SAMPLES_PER_FRAME {
+        let decoded = clamp_i16(decode_sample_unclamped(
+            0, scale, coef1, coef2, *hist1, *hist2,
+        ));
+        *hist2 = *hist1;
+        *hist1 = decoded;
+    }
+
+    let mut encoded_sample_data = [0u8; 7];
+    for (byte, pair) in encoded_sample_data.iter_mut().zip(nibbles.chunks(2)) {
+        *byte = (pair[0] << 4) | pair[1];
+    }
+
+    let frame = Frame {
+        header,
+        encoded_sample_data,
+    };
+    (frame, squared_error)
+}
+
+/// Compute the 8 decode coefficient pairs best suited to encoding `samples`,
+/// which should hold a single audio channel, the same way Nintendo's DSP ADPCM
+/// encoder does.
+///
+/// A second order predictor is fitted to each frame from the autocorrelation of
+/// its samples (solved by LU decomposition, then converted to reflection
+/// coefficients with a Levinson-Durbin step). Frames that are near silent, or
+/// whose predictor would be unstable, are skipped. The average predictor is
+/// then split in two repeatedly, refining the groups after each split, until
+/// there are [`COEFFICIENT_PAIRS_PER_CHANNEL`] of them.
+///
+/// ```
+/// let coefficients = compute_dsp_coefficients(&left_channel_samples);
+/// ```
+#[cfg(feature = "std")]
+pub fn compute_dsp_coefficients(samples: &[i16]) -> [(i16, i16); COEFFICIENT_PAIRS_PER_CHANNEL] {
+    // The previous frame followed by the current one, so that every sample in
+    // the current frame has two samples of history
+    let mut history = [0.0; 2 * SAMPLES_PER_FRAME];
+    let mut records = Vec::new();
+    for frame in samples.chunks(SAMPLES_PER_FRAME) {
+        history.copy_within(SAMPLES_PER_FRAME.., 0);
+        history[SAMPLES_PER_FRAME..].fill(0.0);
+        for (slot, &sample) in history[SAMPLES_PER_FRAME..].iter_mut().zip(frame) {
+            *slot = sample as f64;
+        }
+        records.extend(frame_record(&history));
+    }
+    if records.is_empty() {
+        return [(0, 0); COEFFICIENT_PAIRS_PER_CHANNEL];
+    }
+
+    let mut average = [1.0, 0.0, 0.0];
+    for record in &records {
+        let filtered = matrix_filter(record);
+        average[1] += filtered[1];
+        average[2] += filtered[2];
+    }
+    average[1] /= records.len() as f64;
+    average[2] /= records.len() as f64;
+
+    let mut best = [[0.0; 3]; COEFFICIENT_PAIRS_PER_CHANNEL];
+    best[0] = merge_finish_record(average);
+    let mut count = 1;
+    while count < COEFFICIENT_PAIRS_PER_CHANNEL {
+        for i in 0..count {
+            best[count + i] = [best[i][0], best[i][1] - 0.01, best[i][2]];
+        }
+        count *= 2;
+        filter_records(&mut best[..count], &records);
+    }
+
+    best.map(|vector| (to_fixed_point(-vector[1]), to_fixed_point(-vector[2])))
+}
+
+/// A predictor as `[1, a1, a2]`, where `sample[n] + a1 * sample[n - 1] + a2 *
+/// sample[n - 2] ≈ 0`
+#[cfg(feature = "std")]
+type Vec3 = [f64; 3];
+
+/// Fit a predictor to the second half of `history`, or `None` if the frame is
+/// too quiet or its predictor would be unstable
+#[cfg(feature = "std")]
+fn frame_record(history: &[f64; 2 * SAMPLES_PER_FRAME]) -> Option<Vec3> {
+    let frame = SAMPLES_PER_FRAME;
+
+    let mut vector = [0.0; 3];
+    for (i, value) in vector.iter_mut().enumerate() {
+        for x in frame..2 * frame {
+            *value -= history[x - i] * history[x];
+        }
+    }
+    if vector[0].abs() <= 10.0 {
+        return None;
+    }
+
+    let mut matrix = [[0.0; 3]; 3];
+    for x in 1..=2 {
+        for y in 1..=2 {
+            for z in frame..2 * frame {
+                matrix[x][y] += history[z - x] * history[z - y];
+            }
+        }
+    }
+
+    let indices = lu_decompose(&mut matrix)?;
+    lu_solve(&matrix, &indices, &mut vector);
+    to_reflection(&mut vector)?;
+    Some(finish_record(vector))
+}
+
+/// LU decompose the lower right 2x2 of `matrix` in place with partial
+/// pivoting, returning the row swapped into each position. Returns `None` if
+/// the matrix is singular or badly conditioned.
+#[cfg(feature = "std")]
+#[allow(clippy::needless_range_loop)]
+fn lu_decompose(matrix: &mut [Vec3; 3]) -> Option<[usize; 3]> {
+    let mut reciprocals = [0.0; 3];
+    for x in 1..=2 {
+        let largest = matrix[x][1].abs().max(matrix[x][2].abs());
+        if largest < f64::EPSILON {
+            return None;
+        }
+        reciprocals[x] = 1.0 / largest;
+    }
+
+    let mut indices = [0; 3];
+    let mut max_index = 0;
+    for i in 1..=2 {
+        for x in 1..i {
+            let mut value = matrix[x][i];
+            for y in 1..x {
+                value -= matrix[x][y] * matrix[y][i];
+            }
+            matrix[x][i] = value;
+        }
+
+        let mut largest = 0.0;
+        for x in i..=2 {
+            let mut value = matrix[x][i];
+            for y in 1..i {
+                value -= matrix[x][y] * matrix[y][i];
+            }
+            matrix[x][i] = value;
+            let scaled = value.abs() * reciprocals[x];
+            if scaled >= largest {
+                largest = scaled;
+                max_index = x;
+            }
+        }
+
+        if max_index != i {
+            for y in 1..=2 {
+                let value = matrix[max_index][y];
+                matrix[max_index][y] = matrix[i][y];
+                matrix[i][y] = value;
+            }
+            reciprocals[max_index] = reciprocals[i];
+        }
+        indices[i] = max_index;
+
+        if matrix[i][i] == 0.0 {
+            return None;
+        }
+        if i != 2 {
+            let reciprocal = 1.0 / matrix[i][i];
+            for x in i + 1..=2 {
+                matrix[x][i] *= reciprocal;
+            }
+        }
+    }
+
+    let (min, max) = (1..=2)
+        .map(|i| matrix[i][i].abs())
+        .fold((1.0e10, 0.0), |(min, max), value: f64| {
+            (value.min(min), value.max(max))
+        });
+    if min / max < 1.0e-10 {
+        return None;
+    }
+    Some(indices)
+}
+
+/// Solve for the predictor with a matrix from [`lu_decompose`], replacing the
+/// autocorrelation in `vector` with the predictor
+#[cfg(feature = "std")]
+#[allow(clippy::needless_range_loop)]
+fn lu_solve(matrix: &[Vec3; 3], indices: &[usize; 3], vector: &mut Vec3) {
+    let mut first_nonzero = 0;
+    for i in 1..=2 {
+        let index = indices[i];
+        let mut value = vector[index];
+        vector[index] = vector[i];
+        if first_nonzero != 0 {
+            for y in first_nonzero..i {
+                value -= vector[y] * matrix[i][y];
+            }
+        } else if value != 0.0 {
+            first_nonzero = i;
+        }
+        vector[i] = value;
+    }
+
+    for i in (1..=2).rev() {
+        let mut value = vector[i];
+        for y in i + 1..=2 {
+            value -= vector[y] * matrix[i][y];
+        }
+        vector[i] = value / matrix[i][i];
+    }
+    vector[0] = 1.0;
+}
+
+/// Convert a predictor into reflection coefficients in place, or `None` if it
+/// isn't stable
+#[cfg(feature = "std")]
+fn to_reflection(vector: &mut Vec3) -> Option<()> {
+    let a2 = vector[2];
+    let denominator = 1.0 - a2 * a2;
+    if denominator == 0.0 {
+        return None;
+    }
+    vector[0] = (vector[0] - a2 * a2) / denominator;
+    vector[1] = (vector[1] - vector[1] * a2) / denominator;
+    (vector[1].abs() <= 1.0).then_some(())
+}
+
+/// Convert reflection coefficients back into a predictor, keeping it stable
+#[cfg(feature = "std")]
+fn finish_record(mut reflection: Vec3) -> Vec3 {
+    for value in &mut reflection[1..] {
+        if *value >= 1.0 {
+            *value = 0.9999999999;
+        } else if *value <= -1.0 {
+            *value = -0.9999999999;
+        }
+    }
+    [
+        1.0,
+        reflection[2] * reflection[1] + reflection[1],
+        reflection[2],
+    ]
+}
+
+/// Convert a predictor into the autocorrelation of its impulse response, which
+/// can be averaged with others
+#[cfg(feature = "std")]
+#[allow(clippy::needless_range_loop)]
+fn matrix_filter(predictor: &Vec3) -> Vec3 {
+    let mut matrix = [[0.0; 3]; 3];
+    matrix[2][0] = 1.0;
+    for i in 1..=2 {
+        matrix[2][i] = -predictor[i];
+    }
+    for i in (1..=2).rev() {
+        let denominator = 1.0 - matrix[i][i] * matrix[i][i];
+        for y in 1..=i {
+            matrix[i - 1][y] = (matrix[i][i] * matrix[i][y] + matrix[i][y]) / denominator;
+        }
+    }
+
+    let mut autocorrelation = [1.0, 0.0, 0.0];
+    for i in 1..=2 {
+        for y in 1..=i {
+            autocorrelation[i] += matrix[i][y] * autocorrelation[i - y];
+        }
+    }
+    autocorrelation
+}
+
+/// Convert an autocorrelation back into a predictor with the Levinson-Durbin
+/// recursion. The inverse of [`matrix_filter`].
+#[cfg(feature = "std")]
+#[allow(clippy::needless_range_loop)]
+fn merge_finish_record(autocorrelation: Vec3) -> Vec3 {
+    let mut predictor = [1.0, 0.0, 0.0];
+    let mut reflection = [0.0; 3];
+    let mut error = autocorrelation[0];
+    for i in 1..=2 {
+        let mut sum = 0.0;
+        for y in 1..i {
+            sum += predictor[y] * autocorrelation[i - y];
+        }
+        predictor[i] = if error > 0.0 {
+            -(sum + autocorrelation[i]) / error
+        } else {
+            0.0
+        };
+        reflection[i] = predictor[i];
+        for y in 1..i {
+            predictor[y] += predictor[i] * predictor[i - y];
+        }
+        error *= 1.0 - predictor[i] * predictor[i];
+    }
+    finish_record(reflection)
+}
+
+/// How badly `predictor` would predict audio that `record` fits well
+#[cfg(feature = "std")]
+fn contrast_vectors(predictor: &Vec3, record: &Vec3) -> f64 {
+    let value = (record[2] * record[1] - record[1]) / (1.0 - record[2] * record[2]);
+    let value1 =
+        predictor[0] * predictor[0] + predictor[1] * predictor[1] + predictor[2] * predictor[2];
+    let value2 = predictor[0] * predictor[1] + predictor[1] * predictor[2];
+    let value3 = predictor[0] * predictor[2];
+    value1 + 2.0 * value * value2 + 2.0 * (-record[1] * value - record[2]) * value3
+}
+
+/// Refine the predictors in `best` by assigning every record to the predictor
+/// it contrasts least with, then replacing each predictor with the average of
+/// its records
+#[cfg(feature = "std")]
+fn filter_records(best: &mut [Vec3], records: &[Vec3]) {
+    const REFINEMENT_PASSES: usize = 2;
+
+    for _ in 0..REFINEMENT_PASSES {
+        let mut sums = vec![[0.0; 3]; best.len()];
+        let mut counts = vec![0usize; best.len()];
+        for record in records {
+            let mut nearest = 0;
+            let mut lowest = 1.0e30;
+            for (index, predictor) in best.iter().enumerate() {
+                let contrast = contrast_vectors(predictor, record);
+                if contrast < lowest {
+                    lowest = contrast;
+                    nearest = index;
+                }
+            }
+            counts[nearest] += 1;
+            for (sum, value) in sums[nearest].iter_mut().zip(matrix_filter(record)) {
+                *sum += value;
+            }
+        }
+
+        for ((predictor, sum), count) in best.iter_mut().zip(&mut sums).zip(counts) {
+            if count > 0 {
+                sum.iter_mut().for_each(|value| *value /= count as f64);
+            }
+            *predictor = merge_finish_record(*sum);
+        }
+    }
+}
+
+/// Euclidean distance between two coefficient pairs
+#[cfg(feature = "std")]
+pub(crate) fn predictor_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+/// Convert a coefficient to the 4.11 fixed point format stored in `.hps` files
+#[cfg(feature = "std")]
+fn to_fixed_point(coefficient: f64) -> i16 {
+    (coefficient * 2048.0)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+/// Builds an [`Hps`] from interleaved PCM samples, working out the blocks,
+/// coefficients, decoder states and offsets automatically. Songs are stereo at
+/// 32000 Hz and don't loop unless configured otherwise.
+///
+/// ```
+/// let hps = HpsBuilder::new(&samples)
+///     .sample_rate(32_000)
+///     .channel_count(2)
+///     .loop_start(96_000)
+///     .build()?;
+/// std::fs::write("./my-song.hps", hps.to_bytes())?;
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct HpsBuilder<'a> {
+    samples: &'a [i16],
+    sample_rate: u32,
+    channel_count: u32,
+    loop_start: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl<'a> HpsBuilder<'a> {
+    /// Start building an [`Hps`] from interleaved PCM samples
+    pub fn new(samples: &'a [i16]) -> Self {
+        Self {
+            samples,
+            sample_rate: 32_000,
+            channel_count: 2,
+            loop_start: None,
+        }
+    }
+
+    /// Set the number of samples per second per audio channel
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Set the number of audio channels. Only `1` and `2` are supported.
+    pub fn channel_count(mut self, channel_count: u32) -> Self {
+        self.channel_count = channel_count;
+        self
+    }
+
+    /// Loop back to `sample` (per channel) after reaching the end of the song.
+    /// The loop point is rounded down to the start of a frame.
+    pub fn loop_start(mut self, sample: usize) -> Self {
+        self.loop_start = Some(sample);
+        self
+    }
+
+    /// Encode the samples into an [`Hps`]
+    pub fn build(self) -> Result<Hps, BuildError> {
+        Hps::from_pcm(
+            self.samples,
+            self.sample_rate,
+            self.channel_count,
+            self.loop_start,
+        )
+    }
+}
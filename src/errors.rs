@@ -1,3 +1,6 @@
+//! Error types returned by this crate. [`HpsError`] covers everything needed to
+//! load and decode a file, while the other types describe a specific failure.
+
 use thiserror::Error;
 use winnow::error::{ContextError, ErrMode};
 
@@ -10,17 +13,28 @@ pub enum HpsParseError {
     InvalidMagicNumber,
 
     /// The number of audio channels in the provided file is not supported by the library
-    #[error("Only stereo is supported, but the provided file has {0} audio channel(s)")]
+    #[error("Only mono and stereo are supported, but the provided file has {0} audio channel(s)")]
     UnsupportedChannelCount(u32),
 
+    /// The file is smaller than the sample counts in its channel info imply,
+    /// which usually means it was cut off
+    #[error(
+        "The file appears to be truncated. Expected at least {expected} bytes, but found {actual}"
+    )]
+    Truncated { expected: usize, actual: usize },
+
     #[error("There was not enough data, {0:?} more bytes were needed")]
     Incomplete(winnow::error::Needed),
 
-    #[error("Tried to parse, but encountered invalid data. Cause: {}",
+    #[cfg_attr(feature = "std", error("Tried to parse, but encountered invalid data. Cause: {}",
     match .0.cause() {
         Some(cause) => cause.to_string(),
         None => "None".to_string()
-    })]
+    }))]
+    #[cfg_attr(
+        not(feature = "std"),
+        error("Tried to parse, but encountered invalid data")
+    )]
     InvalidData(ContextError),
 }
 
@@ -35,8 +49,171 @@ impl From<ErrMode<ContextError>> for HpsParseError {
     }
 }
 
+/// Any error that can happen while loading or decoding an `.hps` file. Each
+/// variant wraps the more specific error that caused it, so code that both
+/// parses and decodes can use `?` with a single error type.
+#[derive(Error, Debug)]
+pub enum HpsError {
+    /// The file or stream could not be read
+    #[cfg(feature = "std")]
+    #[error("Failed to read the HPS file: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The file was read, but isn't a valid `.hps` file
+    #[error(transparent)]
+    Parse(#[from] HpsParseError),
+
+    /// The file was parsed, but its audio couldn't be decoded
+    #[error(transparent)]
+    Decode(#[from] HpsDecodeError),
+
+    /// The audio couldn't be encoded into an `.hps` file
+    #[cfg(feature = "std")]
+    #[error(transparent)]
+    Build(#[from] BuildError),
+
+    /// A `.wav` file couldn't be read
+    #[cfg(feature = "wav")]
+    #[error(transparent)]
+    Wav(#[from] hound::Error),
+
+    /// A `.wav` file was read, but its audio can't be converted to HPS. Only
+    /// 16-bit, mono or stereo integer PCM is supported, at 32000 Hz unless the
+    /// `resample` feature is enabled.
+    #[cfg(feature = "wav")]
+    #[error("Unsupported WAV format: {channels} channel(s) of {bits_per_sample}-bit {sample_format:?} samples at {sample_rate} Hz")]
+    UnsupportedWav {
+        channels: u16,
+        bits_per_sample: u16,
+        sample_format: hound::SampleFormat,
+        sample_rate: u32,
+    },
+}
+
 #[derive(Error, Debug)]
 pub enum HpsDecodeError {
-    #[error("One of the audio frame headers contains a coefficient index of {0} which is invalid. Length of the coefficients array is {COEFFICIENT_PAIRS_PER_CHANNEL}")]
-    InvalidCoefficientIndex(usize),
+    /// A frame header refers to a coefficient pair that doesn't exist. The
+    /// frame is the `frame_index`th frame of block `block_index`, and its
+    /// header is `offset` bytes into the file.
+    #[error("The header of frame {frame_index} in block {block_index} (at byte {offset:#x}) contains a coefficient index of {coefficient_index} which is invalid. Length of the coefficients array is {COEFFICIENT_PAIRS_PER_CHANNEL}")]
+    InvalidCoefficientIndex {
+        coefficient_index: usize,
+        block_index: usize,
+        frame_index: usize,
+        offset: u32,
+    },
+}
+
+/// A structural problem found by [`Hps::validate`](crate::Hps::validate)
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HpsValidationWarning {
+    /// A block links to an offset where no block starts
+    #[error("Block {block_index} links to {next_block_offset:#x}, where no block starts")]
+    DanglingBlockLink {
+        block_index: usize,
+        next_block_offset: u32,
+    },
+
+    /// The loop block doesn't exist, or the last block doesn't link to it
+    #[error("The last block doesn't link to the loop block ({0:?})")]
+    UnresolvedLoop(Option<usize>),
+
+    /// A channel's sample count doesn't end within the last block, meaning
+    /// either samples are missing or there are extra blocks
+    #[error("Channel {channel} should have {sample_count} samples, but its blocks hold {decoded_samples}")]
+    SampleCountMismatch {
+        channel: usize,
+        sample_count: u32,
+        decoded_samples: usize,
+    },
+
+    /// A block's DSP data length isn't a whole number of frames
+    #[error("Block {block_index} has a DSP data length of {dsp_data_length}, which isn't a multiple of 8")]
+    PartialFrame {
+        block_index: usize,
+        dsp_data_length: u32,
+    },
+}
+
+/// An error from building an [`Hps`](crate::Hps) with
+/// [`HpsBuilder`](crate::encoder::HpsBuilder)
+#[cfg(feature = "std")]
+#[derive(Error, Debug)]
+pub enum BuildError {
+    /// Only mono and stereo are supported
+    #[error("Only mono and stereo are supported, but {0} channels were given")]
+    UnsupportedChannelCount(u32),
+
+    /// The interleaved samples don't divide evenly between the audio channels
+    #[error("{sample_count} samples can't be split evenly between {channel_count} channels")]
+    PartialSample {
+        sample_count: usize,
+        channel_count: u32,
+    },
+
+    /// The loop point is past the end of the song
+    #[error("Cannot loop from sample {0}, because there are only {1} samples per channel")]
+    LoopStartOutOfRange(usize, usize),
+
+    /// There are no samples to encode, and an `.hps` file needs at least one
+    /// block
+    #[error("There are no samples to encode")]
+    NoSamples,
+}
+
+#[derive(Error, Debug)]
+pub enum LoopError {
+    /// The requested loop block doesn't exist
+    #[error("Cannot loop from block {0}, because there are only {1} blocks")]
+    BlockOutOfRange(usize, usize),
+}
+
+/// An error from playing audio with
+/// [`DecodedHps::play_blocking`](crate::decoded_hps::DecodedHps::play_blocking)
+#[cfg(feature = "cpal-playback")]
+#[derive(Error, Debug)]
+pub enum PlaybackError {
+    /// There is no default audio output device
+    #[error("No audio output device is available")]
+    NoOutputDevice,
+
+    /// The output device can't play audio with the song's channel count, at
+    /// either the song's sample rate or the device's default sample rate
+    #[error("The audio output device doesn't support {0} channel(s) at {1} Hz")]
+    UnsupportedConfig(u32, u32),
+
+    /// The output device uses a sample format that isn't supported
+    #[error("The audio output device uses an unsupported sample format: {0}")]
+    UnsupportedSampleFormat(cpal::SampleFormat),
+
+    #[error(transparent)]
+    SupportedConfigs(#[from] cpal::SupportedStreamConfigsError),
+
+    #[error(transparent)]
+    DefaultConfig(#[from] cpal::DefaultStreamConfigError),
+
+    #[error(transparent)]
+    BuildStream(#[from] cpal::BuildStreamError),
+
+    #[error(transparent)]
+    PlayStream(#[from] cpal::PlayStreamError),
+
+    /// The stream failed while the song was playing
+    #[error(transparent)]
+    Stream(#[from] cpal::StreamError),
+}
+
+#[derive(Error, Debug)]
+pub enum MixError {
+    /// The two pieces of audio being combined have different sample rates
+    #[error("Cannot combine audio with different sample rates ({0} Hz and {1} Hz)")]
+    SampleRateMismatch(u32, u32),
+
+    /// The two pieces of audio being combined have different channel counts
+    #[error("Cannot combine audio with different channel counts ({0} and {1})")]
+    ChannelCountMismatch(u32, u32),
+
+    /// A per-channel setting was given for the wrong number of channels
+    #[error("Expected a value for each of the {0} audio channel(s), but got {1}")]
+    WrongChannelValueCount(u32, usize),
 }
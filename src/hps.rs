@@ -8,6 +8,12 @@
 //! assert_eq!(hps.channel_count, 2);
 //! ```
 //!
+//! Or read and parse a file in one step with [`Hps::try_from_path`]:
+//!
+//! ```
+//! let hps = Hps::try_from_path("./respect-your-elders.hps")?;
+//! ```
+//!
 //! # Decoding into audio
 //! To decode an [`Hps`] into audio, you can use the [`.decode()`](Hps::decode)
 //! method:
@@ -22,30 +28,54 @@
 //! If you’d like to get the underlying PCM samples as a vec, check out the
 //! [`decoded_hps`](crate::decoded_hps) module.
 
-use std::collections::HashSet;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
 
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
-use winnow::prelude::*;
 use winnow::combinator::repeat;
+use winnow::prelude::*;
 
+use crate::checksum::Fnv1a;
 use crate::decoded_hps::DecodedHps;
-use crate::errors::{HpsDecodeError, HpsParseError};
-use crate::parsers::{parse_block, parse_channel_info, parse_file_header};
+#[cfg(feature = "std")]
+use crate::encoder::{compute_dsp_coefficients, predictor_distance};
+use crate::encoder::{encode_frame_with_header, encode_frames};
+#[cfg(feature = "std")]
+use crate::errors::{BuildError, HpsError};
+use crate::errors::{HpsDecodeError, HpsParseError, HpsValidationWarning, LoopError};
+use crate::lazy_decoded_hps::LazyDecodedHps;
+use crate::parsers::{parse_block, parse_channel_info, parse_file_header, MAGIC_NUMBER};
+use crate::writers::{write_block, write_channel_info, write_file_header};
 
 const DSP_BLOCK_SECTION_OFFSET: u32 = 0x80;
 pub(crate) const SAMPLES_PER_FRAME: usize = 14;
+pub(crate) const BYTES_PER_FRAME: usize = 8;
+const BLOCK_HEADER_SIZE: u32 = 0x20;
 pub(crate) const COEFFICIENT_PAIRS_PER_CHANNEL: usize = 8;
+/// Most frames per channel in a block, which matches the files in Super Smash
+/// Bros. Melee and is how many [`Hps::from_pcm`] puts in each block
+#[cfg(any(feature = "std", test))]
+const MAX_FRAMES_PER_BLOCK: usize = 0x1000;
+/// Value of `next_block_offset` in the last block of a song that doesn't loop
+const NO_NEXT_BLOCK: u32 = u32::MAX;
 
 /// A container for HPS file data.
 ///
 /// For general usage, see the [module-level documentation.](crate::hps)
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Hps {
     /// Number of samples per second per audio channel
     pub sample_rate: u32,
     /// Number of audio channels
     pub channel_count: u32,
-    /// Information about the audio channels
+    /// Information about the audio channels. In a mono file, only the first
+    /// entry is used.
     pub channel_info: [ChannelInfo; 2],
     /// DSP Block data
     pub blocks: Vec<Block>,
@@ -58,18 +88,243 @@ impl TryFrom<&[u8]> for Hps {
 
     /// Create an `Hps` from a byte slice
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::parse_bytes(bytes, false, false, BlockLayout::HalfSplit, &mut |_| ())
+    }
+}
+
+impl TryFrom<Vec<u8>> for Hps {
+    type Error = HpsParseError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_slice())
+    }
+}
+
+impl TryFrom<&Vec<u8>> for Hps {
+    type Error = HpsParseError;
+
+    fn try_from(value: &Vec<u8>) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_slice())
+    }
+}
+
+/// How the frames for each audio channel are arranged within a block. Used
+/// with [`Hps::parse_with_layout`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockLayout {
+    /// The first half of the frames in each block are for the left audio
+    /// channel, and the other half are for the right. This is how every
+    /// `.hps` file in Super Smash Bros. Melee is laid out.
+    #[default]
+    HalfSplit,
+    /// Frames alternate between the left and right audio channels, as written
+    /// by some non-standard encoders.
+    Interleaved,
+}
+
+/// Keeps track of how far through an [`Hps`] decoding has gotten, so that its
+/// audio can be decoded a little at a time. Used with
+/// [`Hps::decode_into_ring`].
+///
+/// Only a single block's worth of samples is held in memory at once. A cursor
+/// should only ever be used with the [`Hps`] it started decoding.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecodeCursor {
+    /// Index of the block to decode once `buffer` runs out
+    next_block_index: usize,
+    /// Decoded samples from the current block
+    buffer: Vec<i16>,
+    /// Index of the next sample in `buffer`
+    buffer_index: usize,
+}
+
+impl DecodeCursor {
+    /// Create a cursor positioned at the start of the song
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the next interleaved sample of `hps`, looping back around to
+    /// the loop block at the end of the song. Returns `None` once a song that
+    /// doesn't loop has ended.
+    pub(crate) fn next_sample(&mut self, hps: &Hps) -> Result<Option<i16>, HpsDecodeError> {
+        // Guards against songs that loop over nothing but empty blocks
+        let mut empty_blocks_decoded = 0;
+
+        loop {
+            if let Some(&sample) = self.buffer.get(self.buffer_index) {
+                self.buffer_index += 1;
+                return Ok(Some(sample));
+            }
+
+            // The current block has run out, so decode the next one
+            if self.next_block_index >= hps.blocks.len() {
+                match hps.loop_block_index {
+                    Some(loop_block_index) => self.next_block_index = loop_block_index,
+                    None => return Ok(None),
+                }
+            }
+            self.buffer = hps.decode_block(&hps.blocks[self.next_block_index])?;
+            self.buffer_index = 0;
+            self.next_block_index += 1;
+
+            if self.buffer.is_empty() {
+                empty_blocks_decoded += 1;
+                if empty_blocks_decoded > hps.blocks.len() {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// A compression format that an `.hps` file can be stored in. Used with
+/// [`Hps::from_compressed`].
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// [Zstandard](https://facebook.github.io/zstd/) compression
+    Zstd,
+    /// [Gzip](https://www.gzip.org/) compression
+    Gzip,
+}
+
+impl Hps {
+    /// Read the `.hps` file at `path` and parse it.
+    ///
+    /// ```
+    /// let hps = Hps::try_from_path("./respect-your-elders.hps")?;
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn try_from_path<P: AsRef<std::path::Path>>(path: P) -> Result<Self, HpsError> {
+        let bytes = std::fs::read(path)?;
+        Ok(Self::try_from(bytes)?)
+    }
+
+    /// Parse an `.hps` file from any container of bytes, such as a `Vec<u8>`,
+    /// `Box<[u8]>`, `Arc<[u8]>` or memory-mapped file.
+    ///
+    /// ```
+    /// let bytes: std::sync::Arc<[u8]> = std::fs::read("./respect-your-elders.hps")?.into();
+    /// let hps = Hps::parse(&bytes)?;
+    /// ```
+    pub fn parse(data: impl AsRef<[u8]>) -> Result<Self, HpsParseError> {
+        Self::try_from(data.as_ref())
+    }
+
+    /// Parse `.hps` data embedded within a larger file, such as a game archive,
+    /// starting at byte `start` (where ` HALPST\0` is expected).
+    ///
+    /// Offsets within `.hps` data are relative to its own start, so
+    /// [`Block::offset`] and [`Block::next_block_offset`] are relative to
+    /// `start` rather than to the start of `bytes`.
+    pub fn try_from_at(bytes: &[u8], start: usize) -> Result<Self, HpsParseError> {
+        Self::try_from(bytes.get(start..).unwrap_or_default())
+    }
+
+    /// Parse an `.hps` file without discarding any blocks.
+    ///
+    /// Normally, blocks that aren't linked to by any other block are assumed to
+    /// have been parsed from garbage data and are thrown away. This keeps them,
+    /// which is useful for inspecting everything that was in a file. Use
+    /// [`unreferenced_blocks`](Hps::unreferenced_blocks) to find out which
+    /// blocks would have been discarded.
+    ///
+    /// Note that [`decode`](Hps::decode) will decode every block that is kept,
+    /// including unreferenced ones.
+    pub fn try_from_keep_all(bytes: &[u8]) -> Result<Self, HpsParseError> {
+        Self::parse_bytes(bytes, true, false, BlockLayout::HalfSplit, &mut |_| ())
+    }
+
+    /// Parse an `.hps` file, failing with [`HpsParseError::Truncated`] if it is
+    /// shorter than the sample counts in its channel info imply.
+    ///
+    /// A normal parse silently drops a final block that was cut off, so this is
+    /// useful for catching incomplete downloads before any blocks are parsed.
+    /// The same problem is reported after the fact by
+    /// [`validate`](Hps::validate) as a
+    /// [`SampleCountMismatch`](HpsValidationWarning::SampleCountMismatch).
+    pub fn try_from_strict(bytes: &[u8]) -> Result<Self, HpsParseError> {
+        Self::parse_bytes(bytes, false, true, BlockLayout::HalfSplit, &mut |_| ())
+    }
+
+    /// Parse an `.hps` file whose blocks arrange their frames with the given
+    /// [`BlockLayout`].
+    ///
+    /// The frames are always stored in [`BlockLayout::HalfSplit`] order once
+    /// parsed, so the resulting `Hps` can be used just like any other.
+    pub fn parse_with_layout(
+        data: impl AsRef<[u8]>,
+        layout: BlockLayout,
+    ) -> Result<Self, HpsParseError> {
+        Self::parse_bytes(data.as_ref(), false, false, layout, &mut |_| ())
+    }
+
+    /// Parse an `.hps` file, calling `progress` with the fraction of bytes
+    /// parsed so far (from `0.0` to `1.0`) after each block. This is useful for
+    /// showing a progress bar while loading very large files.
+    pub fn try_from_with_progress(
+        bytes: &[u8],
+        mut progress: impl FnMut(f32),
+    ) -> Result<Self, HpsParseError> {
+        Self::parse_bytes(bytes, false, false, BlockLayout::HalfSplit, &mut progress)
+    }
+
+    /// Parse the raw contents of an `.hps` file. If `keep_unreferenced_blocks`
+    /// is `false`, blocks that no other block links to are discarded. If
+    /// `reject_truncated` is `true`, files too short to hold every sample are
+    /// rejected up front. `progress` is called with the fraction of bytes
+    /// parsed after each block.
+    fn parse_bytes(
+        bytes: &[u8],
+        keep_unreferenced_blocks: bool,
+        reject_truncated: bool,
+        layout: BlockLayout,
+        progress: &mut dyn FnMut(f32),
+    ) -> Result<Self, HpsParseError> {
         let file_size = bytes.len();
         let mut bytes = bytes;
 
+        // Data that ends before the first block is incomplete, as long as what
+        // is there could be the start of an `.hps` file
+        let magic_number_length = file_size.min(MAGIC_NUMBER.len());
+        if file_size < DSP_BLOCK_SECTION_OFFSET as usize
+            && bytes[..magic_number_length] == MAGIC_NUMBER[..magic_number_length]
+        {
+            return Err(HpsParseError::Incomplete(winnow::error::Needed::new(
+                DSP_BLOCK_SECTION_OFFSET as usize - file_size,
+            )));
+        }
+
         // File Header
         let (sample_rate, channel_count) = parse_file_header(&mut bytes)?;
 
         // Left and Right Channel Information
         let left_channel_info = parse_channel_info.parse_next(&mut bytes)?;
         let right_channel_info = parse_channel_info.parse_next(&mut bytes)?;
+        let channel_info = [left_channel_info, right_channel_info];
+
+        // Bail out early if the file is too short to hold every sample that
+        // the channel info says it contains
+        let expected_size = DSP_BLOCK_SECTION_OFFSET as usize
+            + expected_block_bytes(&channel_info[..channel_count as usize]);
+        if reject_truncated && file_size < expected_size {
+            return Err(HpsParseError::Truncated {
+                expected: expected_size,
+                actual: file_size,
+            });
+        }
 
         // Parse the rest of the file as DSP blocks
-        let mut blocks: Vec<Block> = repeat(1.., parse_block(file_size)).parse_next(&mut bytes)?;
+        let mut blocks: Vec<Block> = repeat(
+            1..,
+            parse_block(file_size).map(|block| {
+                let block_end = block.offset + BLOCK_HEADER_SIZE + block.dsp_data_length;
+                progress(block_end as f32 / file_size as f32);
+                block
+            }),
+        )
+        .parse_next(&mut bytes)?;
 
         // Remove any blocks whose `offset` is not referenced by any other
         // blocks' `next_block_offset`
@@ -77,295 +332,3330 @@ impl TryFrom<&[u8]> for Hps {
         // This is specifically to remove any blocks that might have been
         // accidentally parsed from garbage data. While it's extremely unlikely
         // to occur in a real HPS file, better safe than sorry.
-        let valid_block_offsets = std::iter::once(DSP_BLOCK_SECTION_OFFSET)
-            .chain(blocks.iter().map(|b| b.next_block_offset))
-            .collect::<HashSet<_>>();
-        blocks.retain(|b| valid_block_offsets.contains(&b.offset));
+        let valid_block_offsets = referenced_block_offsets(&blocks);
+        if !keep_unreferenced_blocks {
+            blocks.retain(|b| valid_block_offsets.contains(&b.offset));
+        }
 
-        let loop_block_index = blocks.last().and_then(|last_block| {
-            blocks
-                .iter()
-                .position(|block| block.offset == last_block.next_block_offset)
-        });
+        if layout == BlockLayout::Interleaved && channel_count == 2 {
+            for block in &mut blocks {
+                block.deinterleave_frames();
+            }
+        }
+
+        let loop_block_index = blocks
+            .iter()
+            .rev()
+            .find(|block| valid_block_offsets.contains(&block.offset))
+            .and_then(|last_block| {
+                blocks
+                    .iter()
+                    .position(|block| block.offset == last_block.next_block_offset)
+            });
+
+        progress(1.0);
 
         Ok(Hps {
             sample_rate,
             channel_count,
-            channel_info: [left_channel_info, right_channel_info],
+            channel_info,
             blocks,
             loop_block_index,
         })
     }
-}
-
-impl TryFrom<Vec<u8>> for Hps {
-    type Error = HpsParseError;
-
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        Self::try_from(value.as_slice())
-    }
-}
-
-impl TryFrom<&Vec<u8>> for Hps {
-    type Error = HpsParseError;
-
-    fn try_from(value: &Vec<u8>) -> Result<Self, Self::Error> {
-        Self::try_from(value.as_slice())
-    }
-}
 
-impl Hps {
-    /// Decode an [`Hps`] into audio. See the [module-level
-    /// documentation](crate::hps) for more information.
-    pub fn decode(&self) -> Result<DecodedHps, HpsDecodeError> {
-        let samples = self
+    /// Returns `(block_end, next_block_start)` offset pairs for every block
+    /// whose link skips over unparsed bytes to reach the next block. Gaps like
+    /// this can hold embedded data, or be a sign of corruption.
+    ///
+    /// Links that point backwards (such as the loop) are ignored.
+    pub fn offset_gaps(&self) -> Vec<(u32, u32)> {
+        let block_offsets = self
             .blocks
-            .par_iter()
+            .iter()
+            .map(|b| b.offset)
+            .collect::<BTreeSet<_>>();
+        self.blocks
+            .iter()
             .map(|block| {
-                // The first half of the frames in the block are for the left
-                // audio channel, and the other half are for the right
-                let half_index = block.frames.len() / 2;
-
-                // Decode the samples for the left and right audio channels
-                let left_samples = Self::decode_frames(
-                    &block.frames[..half_index],
-                    &block.decoder_states[0],
-                    &self.channel_info[0].coefficients,
-                )?;
-
-                let right_samples = Self::decode_frames(
-                    &block.frames[half_index..],
-                    &block.decoder_states[1],
-                    &self.channel_info[1].coefficients,
-                )?;
-
-                // Interleave the samples with each other
-                Ok(left_samples
-                    .into_iter()
-                    .zip(right_samples)
-                    .flat_map(|(left_sample, right_sample)| [left_sample, right_sample]))
+                let block_end = block.offset + BLOCK_HEADER_SIZE + block.dsp_data_length;
+                (block_end, block.next_block_offset)
             })
-            .collect::<Result<Vec<_>, HpsDecodeError>>()?
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-
-        Ok(DecodedHps::new(self, samples))
+            .filter(|(block_end, next_block_start)| {
+                next_block_start > block_end && block_offsets.contains(next_block_start)
+            })
+            .collect()
     }
 
-    /// Decode a slice of DSP block frames into samples
-    fn decode_frames(
-        frames: &[Frame],
-        decoder_state: &DSPDecoderState,
-        coefficients: &[(i16, i16)],
-    ) -> Result<Vec<i16>, HpsDecodeError> {
-        let sample_count = frames.len() * SAMPLES_PER_FRAME;
-        let mut samples: Vec<i16> = Vec::with_capacity(sample_count);
+    /// Encode interleaved PCM samples into an [`Hps`], which can then be
+    /// written to an `.hps` file with [`to_bytes`](Hps::to_bytes).
+    ///
+    /// Decode coefficients are computed from each audio channel, then every
+    /// frame is encoded with whichever coefficient pair and scale reproduce it
+    /// most accurately. If `loop_start` is given, the song loops back to that
+    /// sample (per channel), rounded down to the start of a frame.
+    ///
+    /// Returns an error if `channel_count` is not `1` or `2`, if `samples` is
+    /// empty or can't be split evenly between the channels, or if `loop_start`
+    /// is past the end of the song.
+    #[cfg(feature = "std")]
+    pub fn from_pcm(
+        samples: &[i16],
+        sample_rate: u32,
+        channel_count: u32,
+        loop_start: Option<usize>,
+    ) -> Result<Hps, BuildError> {
+        if !matches!(channel_count, 1 | 2) {
+            return Err(BuildError::UnsupportedChannelCount(channel_count));
+        }
+        if samples.is_empty() {
+            return Err(BuildError::NoSamples);
+        }
+        if !samples.len().is_multiple_of(channel_count as usize) {
+            return Err(BuildError::PartialSample {
+                sample_count: samples.len(),
+                channel_count,
+            });
+        }
+        let samples_per_channel = samples.len() / channel_count as usize;
+        if let Some(loop_start) = loop_start.filter(|&s| s >= samples_per_channel) {
+            return Err(BuildError::LoopStartOutOfRange(
+                loop_start,
+                samples_per_channel,
+            ));
+        }
 
-        let mut hist1 = decoder_state.initial_hist_1;
-        let mut hist2 = decoder_state.initial_hist_2;
+        let channel_count = channel_count as usize;
+        let channel_samples: Vec<Vec<i16>> = (0..channel_count)
+            .map(|channel| {
+                samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channel_count)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+        let sample_count = channel_samples[0].len();
+        let frame_count = sample_count.div_ceil(SAMPLES_PER_FRAME);
 
-        for frame in frames {
-            let scale = 1 << (frame.header & 0xF);
-            let coef_index = (frame.header >> 4) as usize;
-            if coef_index >= COEFFICIENT_PAIRS_PER_CHANNEL {
-                return Err(HpsDecodeError::InvalidCoefficientIndex(coef_index));
+        // Blocks can only loop back to their start, so the song is split into
+        // blocks at the loop point
+        let loop_frame = loop_start.map(|sample| sample / SAMPLES_PER_FRAME);
+        let mut block_ranges = Vec::new();
+        let mut frame = 0;
+        while frame < frame_count {
+            let block_end = match loop_frame {
+                Some(loop_frame) if frame < loop_frame => loop_frame,
+                _ => frame_count,
             }
-            let (coef1, coef2) = coefficients[coef_index];
+            .min(frame + MAX_FRAMES_PER_BLOCK);
+            block_ranges.push(frame..block_end);
+            frame = block_end;
+        }
+        let loop_block_index = loop_frame
+            .and_then(|loop_frame| block_ranges.iter().position(|r| r.start == loop_frame));
 
-            frame
-                .encoded_sample_data
-                .iter()
-                .flat_map(|&byte| [get_high_nibble(byte), get_low_nibble(byte)])
-                .for_each(|nibble| {
-                    let sample = clamp_i16(
-                        (((nibble as i32 * scale) << 11)
-                            + 1024
-                            + (coef1 as i32 * hist1 as i32 + coef2 as i32 * hist2 as i32))
-                            >> 11,
-                    );
+        let coefficients: Vec<_> = channel_samples
+            .iter()
+            .map(|samples| compute_dsp_coefficients(samples))
+            .collect();
 
-                    hist2 = hist1;
-                    hist1 = sample;
-                    samples.push(sample);
-                });
+        // Encode each channel block by block, noting down the decoder history
+        // at the start of each block
+        let mut blocks: Vec<Block> = block_ranges
+            .iter()
+            .map(|_| Block {
+                offset: 0,
+                dsp_data_length: 0,
+                next_block_offset: 0,
+                decoder_states: Default::default(),
+                frames: Vec::new(),
+            })
+            .collect();
+        for (channel, samples) in channel_samples.iter().enumerate() {
+            let mut decoder_state = DSPDecoderState::default();
+            for (block, range) in blocks.iter_mut().zip(&block_ranges) {
+                let start = range.start * SAMPLES_PER_FRAME;
+                let end = (range.end * SAMPLES_PER_FRAME).min(sample_count);
+                let initial_decoder_state = decoder_state.clone();
+                let frames = encode_frames(
+                    &samples[start..end],
+                    &coefficients[channel],
+                    &mut decoder_state,
+                );
+                for state in &mut block.decoder_states[channel..] {
+                    state.clone_from(&initial_decoder_state);
+                    state.ps = frames.first().map_or(0, |frame| frame.header);
+                }
+                block.frames.extend(frames);
+            }
         }
 
-        Ok(samples)
-    }
-}
+        // Lay the blocks out back to back, linking the last one to the loop
+        // block
+        let mut offset = DSP_BLOCK_SECTION_OFFSET;
+        for block in &mut blocks {
+            block.offset = offset;
+            block.dsp_data_length = (block.frames.len() * BYTES_PER_FRAME) as u32;
+            offset += BLOCK_HEADER_SIZE + block.dsp_data_length;
+            block.next_block_offset = offset;
+        }
+        let loop_block_offset =
+            loop_block_index.map_or(NO_NEXT_BLOCK, |index| blocks[index].offset);
+        if let Some(last_block) = blocks.last_mut() {
+            last_block.next_block_offset = loop_block_offset;
+        }
 
-/// Information about an audio channel. Notably, an audio channel contains 16
-/// "coefficients" that are used in the calculation to decode samples.
-#[derive(Debug, PartialEq, Clone)]
-pub struct ChannelInfo {
-    pub largest_block_length: u32,
-    pub sample_count: u32,
-    pub coefficients: [(i16, i16); COEFFICIENT_PAIRS_PER_CHANNEL],
-}
+        let largest_block_length = blocks.iter().map(|b| b.dsp_data_length).max().unwrap_or(0);
+        let channel_info = |channel: usize| ChannelInfo {
+            largest_block_length,
+            sample_count: sample_count as u32,
+            coefficients: coefficients[channel.min(channel_count - 1)],
+            initial_decoder_state: DSPDecoderState::default(),
+        };
 
-/// The audio data contained in an [`Hps`] is split into multiple "blocks", each
-/// containing [`Frame`]s of encoded samples as well as a link to the start of the
-/// next block.
-///
-/// In a stereo [`Hps`], the first half of the frames in each block are for the
-/// left audio channel, and other half are for the right.
-#[derive(Debug, Clone, PartialEq)]
-pub struct Block {
-    pub offset: u32,
-    pub dsp_data_length: u32,
-    pub next_block_offset: u32,
-    pub decoder_states: [DSPDecoderState; 2],
-    pub frames: Vec<Frame>,
-}
+        Ok(Hps {
+            sample_rate,
+            channel_count: channel_count as u32,
+            channel_info: [channel_info(0), channel_info(1)],
+            blocks,
+            loop_block_index,
+        })
+    }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct DSPDecoderState {
-    // ps_hi: u8, // unused?
-    // ps: u8,    // unused?
-    pub initial_hist_1: i16,
-    pub initial_hist_2: i16,
-}
+    /// Serialize the [`Hps`] into the bytes of an `.hps` file.
+    ///
+    /// Blocks are written back to back in order, each linking to the one after
+    /// it. The last block links back to the loop block, if there is one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let channel_count = self.channel_count as usize;
+        let frame_bytes =
+            self.blocks.iter().map(|b| b.frames.len()).sum::<usize>() * BYTES_PER_FRAME;
+        let mut bytes = Vec::with_capacity(
+            DSP_BLOCK_SECTION_OFFSET as usize
+                + self.blocks.len() * BLOCK_HEADER_SIZE as usize
+                + frame_bytes,
+        );
 
-/// Each frame of audio data contains 14 encoded PCM samples.
-#[derive(Debug, Clone, PartialEq)]
-pub struct Frame {
-    pub header: u8,
-    pub encoded_sample_data: [u8; 7],
-}
+        write_file_header(&mut bytes, self.sample_rate, self.channel_count);
+        for info in &self.channel_info {
+            write_channel_info(&mut bytes, info);
+        }
 
-static NIBBLE_TO_I8: [i8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, -8, -7, -6, -5, -4, -3, -2, -1];
+        let offsets = self
+            .blocks
+            .iter()
+            .scan(DSP_BLOCK_SECTION_OFFSET, |offset, block| {
+                let block_offset = *offset;
+                *offset += BLOCK_HEADER_SIZE + (block.frames.len() * BYTES_PER_FRAME) as u32;
+                Some(block_offset)
+            })
+            .collect::<Vec<_>>();
+        let sample_count = self.channel_info[0].sample_count as usize;
+        let mut samples_before_block = 0;
 
-#[inline(always)]
-fn get_low_nibble(byte: u8) -> i8 {
-    NIBBLE_TO_I8[(byte & 0xF) as usize]
-}
+        for (index, block) in self.blocks.iter().enumerate() {
+            let next_block_offset = match offsets.get(index + 1) {
+                Some(&offset) => offset,
+                None => self
+                    .loop_block_index
+                    .map_or(NO_NEXT_BLOCK, |loop_block_index| offsets[loop_block_index]),
+            };
 
-#[inline(always)]
-fn get_high_nibble(byte: u8) -> i8 {
-    NIBBLE_TO_I8[((byte >> 4) & 0xF) as usize]
-}
+            // The nibble address of the last sample that plays in each
+            // channel, counting the two header nibbles of each frame
+            let block_samples = block.channel_sample_count(channel_count);
+            let samples_played = sample_count
+                .saturating_sub(samples_before_block)
+                .min(block_samples)
+                .max(1);
+            let last_sample = samples_played - 1;
+            let end_address = (last_sample / SAMPLES_PER_FRAME * BYTES_PER_FRAME * 2
+                + 2
+                + last_sample % SAMPLES_PER_FRAME) as u32;
+            samples_before_block += block_samples;
 
-#[inline(always)]
-fn clamp_i16(val: i32) -> i16 {
-    if val < (i16::MIN as i32) {
-        i16::MIN
-    } else if val > (i16::MAX as i32) {
-        i16::MAX
-    } else {
-        val as i16
-    }
-}
+            write_block(
+                &mut bytes,
+                block,
+                channel_count,
+                end_address,
+                next_block_offset,
+            );
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        bytes
+    }
 
-    #[test]
-    fn decodes_blocks_correctly() {
-        let hps: Hps = std::fs::read("test-data/test-song.hps")
-            .unwrap()
-            .try_into()
-            .unwrap();
+    /// Read a `.wav` file and encode it into a non-looping [`Hps`] with
+    /// [`from_pcm`](Hps::from_pcm).
+    ///
+    /// ```
+    /// let file = std::fs::File::open("./my-song.wav")?;
+    /// let hps = Hps::from_wav_reader(std::io::BufReader::new(file))?;
+    /// std::fs::write("./my-song.hps", hps.to_bytes())?;
+    /// ```
+    ///
+    /// The audio must be 16-bit mono or stereo integer PCM at 32000 Hz, the
+    /// rate Melee plays music at. With the `resample` feature enabled, other
+    /// sample rates are converted to 32000 Hz instead of being rejected.
+    #[cfg(feature = "wav")]
+    pub fn from_wav_reader<R: std::io::Read>(reader: R) -> Result<Self, HpsError> {
+        const SAMPLE_RATE: u32 = 32_000;
 
-        let decoded_bytes = hps
-            .decode()
-            .unwrap()
-            .samples()
-            .into_iter()
-            .flat_map(|sample| sample.to_be_bytes())
-            .collect::<Vec<_>>();
+        let reader = hound::WavReader::new(reader)?;
+        let spec = reader.spec();
+        let supported = matches!(spec.channels, 1 | 2)
+            && spec.bits_per_sample == 16
+            && spec.sample_format == hound::SampleFormat::Int
+            && (spec.sample_rate == SAMPLE_RATE || cfg!(feature = "resample"));
+        if !supported {
+            return Err(HpsError::UnsupportedWav {
+                channels: spec.channels,
+                bits_per_sample: spec.bits_per_sample,
+                sample_format: spec.sample_format,
+                sample_rate: spec.sample_rate,
+            });
+        }
 
-        // // Create a new binary file of decoded samples for testing
-        // use std::io::prelude::*;
-        // let mut file = std::fs::File::create("new.bin").unwrap();
-        // file.write_all(&decoded_bytes).unwrap();
+        let samples = reader
+            .into_samples::<i16>()
+            .collect::<Result<Vec<_>, _>>()?;
+        #[cfg(feature = "resample")]
+        let samples = match spec.sample_rate {
+            SAMPLE_RATE => samples,
+            sample_rate => DecodedHps::from_samples(samples, sample_rate, spec.channels as u32)
+                .resample(SAMPLE_RATE)
+                .samples()
+                .to_vec(),
+        };
 
-        let expected_bytes = std::fs::read("test-data/test-song-decoded.bin").unwrap();
-        assert_eq!(expected_bytes, decoded_bytes);
+        Ok(Self::from_pcm(
+            &samples,
+            SAMPLE_RATE,
+            spec.channels as u32,
+            None,
+        )?)
     }
 
-    #[test]
-    fn doesnt_include_any_blocks_more_than_once() {
-        let hps: Hps = std::fs::read("test-data/test-song.hps")
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let block_count = hps.blocks.len();
-        let unique_block_offsets = hps
-            .blocks
+    /// Returns the indices of any blocks that aren't linked to by another block.
+    ///
+    /// A normal parse discards unreferenced blocks, but only in a single pass,
+    /// so a block that was only linked to by a discarded block is kept and
+    /// shows up here. Every unreferenced block is kept by
+    /// [`try_from_keep_all`](Hps::try_from_keep_all).
+    pub fn unreferenced_blocks(&self) -> Vec<usize> {
+        let valid_block_offsets = referenced_block_offsets(&self.blocks);
+        self.blocks
             .iter()
-            .map(|block| block.offset)
-            .collect::<HashSet<_>>();
-        let unique_block_count = unique_block_offsets.len();
-        assert_eq!(block_count, unique_block_count);
+            .enumerate()
+            .filter(|(_, block)| !valid_block_offsets.contains(&block.offset))
+            .map(|(index, _)| index)
+            .collect()
     }
 
-    #[test]
-    fn parses_last_block_even_if_its_very_short() {
-        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
-            .unwrap()
-            .try_into()
-            .unwrap();
-
-        assert_eq!(hps.blocks.len(), 8);
-        assert!(hps.loop_block_index.is_some());
+    /// Read an `.hps` file from any [`Read`](std::io::Read)er, such as a file
+    /// handle, network stream or zip entry, then parse it into an [`Hps`].
+    ///
+    /// ```
+    /// let file = std::fs::File::open("./respect-your-elders.hps")?;
+    /// let hps = Hps::from_reader(std::io::BufReader::new(file))?;
+    /// ```
+    ///
+    /// If the stream ends before the header and channel info have been read,
+    /// [`HpsParseError::Incomplete`] reports how many more bytes were needed.
+    #[cfg(feature = "std")]
+    pub fn from_reader<R: std::io::Read>(mut reader: R) -> Result<Self, HpsError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(Self::try_from(bytes.as_slice())?)
     }
 
-    #[test]
-    fn properly_handles_invalid_coefficient_index() {
-        let hps: Hps = std::fs::read("test-data/corrupt-dsp-frame-header.hps")
-            .unwrap()
-            .try_into()
-            .unwrap();
-
-        assert!(matches!(
-            hps.decode().unwrap_err(),
-            HpsDecodeError::InvalidCoefficientIndex(..)
-        ));
+    /// Decompress an `.hps` file from `reader` using the given [`Codec`], then
+    /// parse it into an [`Hps`].
+    ///
+    /// ```
+    /// let file = std::fs::File::open("./respect-your-elders.hps.zst")?;
+    /// let hps = Hps::from_compressed(file, Codec::Zstd)?;
+    /// ```
+    #[cfg(feature = "compression")]
+    pub fn from_compressed<R: std::io::Read>(reader: R, codec: Codec) -> Result<Self, HpsError> {
+        match codec {
+            Codec::Zstd => Self::from_reader(zstd::stream::read::Decoder::new(reader)?),
+            Codec::Gzip => Self::from_reader(flate2::read::GzDecoder::new(reader)),
+        }
     }
 
-    #[test]
+    /// Decode an [`Hps`] into audio. See the [module-level
+    /// documentation](crate::hps) for more information.
+    ///
+    /// With the `rodio-source` feature, the decoded audio is itself a rodio
+    /// source that plays the intro once and then loops forever from
+    /// [`loop_block_index`](Hps::loop_block_index), so it can be played
+    /// directly. Songs that don't loop play once.
+    ///
+    /// ```
+    /// sink.append(hps.decode()?);
+    /// ```
+    pub fn decode(&self) -> Result<DecodedHps, HpsDecodeError> {
+        let samples = self.decode_blocks(&self.blocks)?;
+        Ok(DecodedHps::new(self, samples))
+    }
+
+    /// Decode an [`Hps`] into the caller's buffer, reusing its allocation. The
+    /// buffer is cleared first, then filled with the same interleaved samples
+    /// as [`DecodedHps::samples`].
+    ///
+    /// Blocks are decoded one at a time, straight into `buf`, so this is useful
+    /// for decoding many files in a row without allocating for each one.
+    pub fn decode_into(&self, buf: &mut Vec<i16>) -> Result<(), HpsDecodeError> {
+        buf.clear();
+        buf.reserve(self.decoded_sample_count());
+        for block in &self.blocks {
+            buf.extend(self.decode_block(block)?);
+        }
+        Ok(())
+    }
+
+    /// Turn an [`Hps`] into an iterator that decodes its audio one block at a
+    /// time, rather than all at once like [`decode`](Hps::decode). See the
+    /// [`lazy_decoded_hps`](crate::lazy_decoded_hps) module for more
+    /// information.
+    pub fn decode_lazy(self) -> Result<LazyDecodedHps, HpsDecodeError> {
+        LazyDecodedHps::new(self)
+    }
+
+    /// Returns `true` if [`decode`](Hps::decode) will spread the work across
+    /// multiple threads. This is the case when there is more than one block to
+    /// decode and rayon's thread pool has more than one thread.
+    ///
+    /// Without the `parallel` feature, this is always `false`.
+    pub fn will_parallelize(&self) -> bool {
+        #[cfg(feature = "parallel")]
+        return self.blocks.len() > 1 && rayon::current_num_threads() > 1;
+        #[cfg(not(feature = "parallel"))]
+        return false;
+    }
+
+    /// Decode only the part of the song between `start` and `end` into
+    /// non-looping audio.
+    ///
+    /// Only the blocks that overlap the requested window are decoded, and the
+    /// result is then trimmed down to the exact sample, so this is much cheaper
+    /// than decoding the whole song when the window is short. The window is
+    /// clamped to the length of the song, ignoring any looping.
+    pub fn decode_range(
+        &self,
+        start: Duration,
+        end: Duration,
+    ) -> Result<DecodedHps, HpsDecodeError> {
+        let start = duration_to_samples(start, self.sample_rate);
+        let end = duration_to_samples(end, self.sample_rate).max(start);
+
+        // Find the blocks that overlap the window, along with the sample that
+        // the first of them starts at
+        let mut first_block = self.blocks.len();
+        let mut last_block = self.blocks.len();
+        let mut first_block_start = 0;
+        let mut block_start = 0;
+        let channel_count = self.channel_count as usize;
+        for (index, block) in self.blocks.iter().enumerate() {
+            let block_end = block_start + block.channel_sample_count(channel_count);
+            if block_end > start && first_block == self.blocks.len() {
+                first_block = index;
+                first_block_start = block_start;
+            }
+            if block_end >= end {
+                last_block = index + 1;
+                break;
+            }
+            block_start = block_end;
+        }
+        let blocks = &self.blocks[first_block.min(last_block)..last_block];
+
+        let mut samples = self.decode_blocks(blocks)?;
+        let trim_end = ((end - first_block_start.min(end)) * channel_count).min(samples.len());
+        let trim_start = ((start - first_block_start.min(start)) * channel_count).min(trim_end);
+        samples.truncate(trim_end);
+        samples.drain(..trim_start);
+
+        Ok(DecodedHps::from_samples(
+            samples,
+            self.sample_rate,
+            self.channel_count,
+        ))
+    }
+
+    /// Decode an [`Hps`] into interleaved PCM samples stored in the given
+    /// allocator, for applications that manage their own memory (arenas, game
+    /// engine allocators, etc).
+    ///
+    /// This uses the [`Allocator`](allocator_api2::alloc::Allocator) trait from
+    /// [`allocator-api2`](https://docs.rs/allocator-api2), which works on
+    /// stable Rust and mirrors the standard library's unstable
+    /// `allocator_api`. On nightly, enabling `allocator-api2`'s `nightly`
+    /// feature makes it accept any `std::alloc::Allocator`.
+    ///
+    /// Only the returned samples are placed in `alloc`. Blocks are decoded one
+    /// at a time, so temporary allocations never exceed a single block.
+    #[cfg(feature = "allocator-api")]
+    pub fn decode_in<A: allocator_api2::alloc::Allocator>(
+        &self,
+        alloc: A,
+    ) -> Result<allocator_api2::vec::Vec<i16, A>, HpsDecodeError> {
+        let mut samples =
+            allocator_api2::vec::Vec::with_capacity_in(self.decoded_sample_count(), alloc);
+        for block in &self.blocks {
+            samples.extend(self.decode_block(block)?);
+        }
+        Ok(samples)
+    }
+
+    /// Decode the song and summarize it as `width` `(min, max)` sample pairs,
+    /// suitable for drawing a waveform thumbnail. Looping is ignored.
+    ///
+    /// Blocks are decoded one at a time and folded straight into the summary,
+    /// so the full song is never held in memory. Each pair covers an equal
+    /// share of the song across all channels. If `width` is larger than the
+    /// number of samples per channel, the pairs that don't cover any samples
+    /// are `(0, 0)`.
+    pub fn thumbnail(&self, width: usize) -> Result<Vec<(i16, i16)>, HpsDecodeError> {
+        let channel_count = self.channel_count as usize;
+        let total_samples = self.samples_before_block(self.blocks.len());
+
+        let mut buckets = vec![(i16::MAX, i16::MIN); width];
+        if width > 0 {
+            for (block, block_start) in self.blocks_with_offsets() {
+                let block_start = block_start / channel_count;
+                let samples = self.decode_block(block)?;
+
+                for (i, channel_samples) in samples.chunks(channel_count).enumerate() {
+                    let (min, max) = &mut buckets[(block_start + i) * width / total_samples];
+                    for &sample in channel_samples {
+                        *min = sample.min(*min);
+                        *max = sample.max(*max);
+                    }
+                }
+            }
+        }
+
+        Ok(buckets
+            .into_iter()
+            .map(|(min, max)| if min > max { (0, 0) } else { (min, max) })
+            .collect())
+    }
+
+    /// Decode the next `count` interleaved samples from `cursor` into `ring`,
+    /// starting at `write_pos` and wrapping around to the start of `ring` when
+    /// the end is reached. `write_pos` is left just after the last sample
+    /// written. Looping songs loop back around as they're decoded.
+    ///
+    /// This is a low level building block for audio engines that feed a fixed
+    /// size ring buffer, since no more than a single block is ever decoded
+    /// ahead of time. Returns the number of samples written, which is less
+    /// than `count` if a song that doesn't loop runs out.
+    pub fn decode_into_ring(
+        &self,
+        cursor: &mut DecodeCursor,
+        ring: &mut [i16],
+        write_pos: &mut usize,
+        count: usize,
+    ) -> Result<usize, HpsDecodeError> {
+        if ring.is_empty() {
+            return Ok(0);
+        }
+        for written in 0..count {
+            let Some(sample) = cursor.next_sample(self)? else {
+                return Ok(written);
+            };
+            *write_pos %= ring.len();
+            ring[*write_pos] = sample;
+            *write_pos = (*write_pos + 1) % ring.len();
+        }
+        Ok(count)
+    }
+
+    /// Returns the raw encoded bytes of every frame of the given audio channel
+    /// (`0` for left, `1` for right) that holds any of the samples from
+    /// `start_sample` up to `end_sample`. Each frame is 8 bytes: a header
+    /// followed by 14 encoded samples.
+    ///
+    /// Sample indices count samples per channel, ignoring looping. Since
+    /// frames hold 14 samples each, the bytes may cover a few samples either
+    /// side of the range.
+    pub fn encoded_bytes_for_range(
+        &self,
+        channel: usize,
+        start_sample: usize,
+        end_sample: usize,
+    ) -> Vec<u8> {
+        let channel_count = self.channel_count as usize;
+        if channel >= channel_count {
+            return Vec::new();
+        }
+
+        self.blocks
+            .iter()
+            .flat_map(|block| block.channel_frames(channel, channel_count))
+            .enumerate()
+            .skip(start_sample / SAMPLES_PER_FRAME)
+            .take_while(|(index, _)| index * SAMPLES_PER_FRAME < end_sample)
+            .flat_map(|(_, frame)| core::iter::once(frame.header).chain(frame.encoded_sample_data))
+            .collect()
+    }
+
+    /// Decode the song one block at a time, handing each block's index and
+    /// interleaved samples to `f` before moving on to the next. Looping is
+    /// ignored.
+    ///
+    /// Only a single block is ever held in memory, so this is useful for
+    /// computing running statistics over very large files.
+    pub fn analyze_streaming(
+        &self,
+        mut f: impl FnMut(usize, &[i16]),
+    ) -> Result<(), HpsDecodeError> {
+        for (index, block) in self.blocks.iter().enumerate() {
+            f(index, &self.decode_block(block)?);
+        }
+        Ok(())
+    }
+
+    /// Decode several [`Hps`]es, such as a whole folder of music, returning
+    /// each one's result in the same order as `items`.
+    ///
+    /// With the `parallel` feature, whole files are spread across rayon's
+    /// global thread pool, and each file's blocks are decoded one after another
+    /// on whichever thread picks it up. Parallelism is never nested, so at most
+    /// [`current_num_threads`](https://docs.rs/rayon/latest/rayon/fn.current_num_threads.html)
+    /// files are decoded at once, and CPU usage is bounded by the size of the
+    /// pool. A single file is decoded with
+    /// [`decode`](Hps::decode) instead, so its blocks still run in parallel.
+    ///
+    /// Without the `parallel` feature, files are decoded one after another on
+    /// the current thread.
+    pub fn decode_many<I: IntoIterator<Item = Hps>>(
+        items: I,
+    ) -> Vec<Result<DecodedHps, HpsDecodeError>> {
+        let items = items.into_iter().collect::<Vec<_>>();
+        if let [hps] = items.as_slice() {
+            return vec![hps.decode()];
+        }
+
+        #[cfg(feature = "parallel")]
+        let items = items.into_par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let items = items.into_iter();
+        items
+            .map(|hps| {
+                let samples = hps.decode_blocks_sequential(&hps.blocks)?;
+                Ok(DecodedHps::new(&hps, samples))
+            })
+            .collect()
+    }
+
+    /// Decode a set of blocks into interleaved samples
+    #[cfg(feature = "parallel")]
+    fn decode_blocks(&self, blocks: &[Block]) -> Result<Vec<i16>, HpsDecodeError> {
+        if blocks.len() <= 1 || rayon::current_num_threads() <= 1 {
+            return self.decode_blocks_sequential(blocks);
+        }
+
+        Ok(blocks
+            .par_iter()
+            .map(|block| self.decode_block(block))
+            .collect::<Result<Vec<_>, HpsDecodeError>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>())
+    }
+
+    /// Decode a set of blocks into interleaved samples
+    #[cfg(not(feature = "parallel"))]
+    fn decode_blocks(&self, blocks: &[Block]) -> Result<Vec<i16>, HpsDecodeError> {
+        self.decode_blocks_sequential(blocks)
+    }
+
+    /// Decode a set of blocks into interleaved samples one after another, on
+    /// the current thread
+    fn decode_blocks_sequential(&self, blocks: &[Block]) -> Result<Vec<i16>, HpsDecodeError> {
+        let mut samples = Vec::new();
+        for block in blocks {
+            samples.extend(self.decode_block(block)?);
+        }
+        Ok(samples)
+    }
+
+    /// Decode a single block into interleaved samples
+    pub(crate) fn decode_block(&self, block: &Block) -> Result<Vec<i16>, HpsDecodeError> {
+        let channel_count = self.channel_count as usize;
+
+        // Decode the samples for the left and right audio channels
+        let left_samples = Self::decode_frames(
+            block.channel_frames(0, channel_count),
+            &block.decoder_states[0],
+            &self.channel_info[0].coefficients,
+        )
+        .ok_or_else(|| self.invalid_frame_error(block))?;
+        if channel_count == 1 {
+            return Ok(left_samples);
+        }
+
+        let right_samples = Self::decode_frames(
+            block.channel_frames(1, channel_count),
+            &block.decoder_states[1],
+            &self.channel_info[1].coefficients,
+        )
+        .ok_or_else(|| self.invalid_frame_error(block))?;
+
+        // Interleave the samples with each other
+        Ok(left_samples
+            .into_iter()
+            .zip(right_samples)
+            .flat_map(|(left_sample, right_sample)| [left_sample, right_sample])
+            .collect())
+    }
+
+    /// Count how many blocks have each distinct
+    /// [`dsp_data_length`](Block::dsp_data_length). Encoders tend to use
+    /// characteristic block sizes, so this helps identify which tool made a
+    /// file.
+    pub fn block_size_histogram(&self) -> BTreeMap<u32, usize> {
+        let mut histogram = BTreeMap::new();
+        for block in &self.blocks {
+            *histogram.entry(block.dsp_data_length).or_default() += 1;
+        }
+        histogram
+    }
+
+    /// Returns the distinct frame header bytes (coefficient index and scale)
+    /// used across the whole file, in ascending order. Simple encoders tend to
+    /// use only a few, while more adaptive ones use many.
+    pub fn frame_header_variety(&self) -> BTreeSet<u8> {
+        self.blocks
+            .iter()
+            .flat_map(|block| &block.frames)
+            .map(|frame| frame.header)
+            .collect()
+    }
+
+    /// Check the structure of the file, collecting every problem found rather
+    /// than stopping at the first one. This checks that:
+    ///
+    /// - every block links to another block, or marks the end of the song
+    /// - the last block links to the [loop block](Hps::loop_block_index), if
+    ///   there is one
+    /// - each channel's [`sample_count`](ChannelInfo::sample_count) ends within
+    ///   the last block
+    /// - each block's [`dsp_data_length`](Block::dsp_data_length) is a whole
+    ///   number of frames
+    ///
+    /// Frame headers aren't checked; see
+    /// [`validate_frame_headers`](Hps::validate_frame_headers) for that.
+    pub fn validate(&self) -> Result<(), Vec<HpsValidationWarning>> {
+        let mut warnings = Vec::new();
+        let block_offsets = self
+            .blocks
+            .iter()
+            .map(|b| b.offset)
+            .collect::<BTreeSet<_>>();
+
+        for (block_index, block) in self.blocks.iter().enumerate() {
+            let next_block_offset = block.next_block_offset;
+            if next_block_offset != NO_NEXT_BLOCK && !block_offsets.contains(&next_block_offset) {
+                warnings.push(HpsValidationWarning::DanglingBlockLink {
+                    block_index,
+                    next_block_offset,
+                });
+            }
+            if block.dsp_data_length % BYTES_PER_FRAME as u32 != 0 {
+                warnings.push(HpsValidationWarning::PartialFrame {
+                    block_index,
+                    dsp_data_length: block.dsp_data_length,
+                });
+            }
+        }
+
+        let expected_link = match self.loop_block_index {
+            Some(index) => self.blocks.get(index).map(|block| block.offset),
+            None => Some(NO_NEXT_BLOCK),
+        };
+        let last_link = self.blocks.last().map(|block| block.next_block_offset);
+        if last_link.is_some() && last_link != expected_link {
+            warnings.push(HpsValidationWarning::UnresolvedLoop(self.loop_block_index));
+        }
+
+        let decoded_samples = self.samples_before_block(self.blocks.len());
+        for (channel, info) in self
+            .channel_info
+            .iter()
+            .enumerate()
+            .take(self.channel_count as usize)
+        {
+            if !self.sample_count_fits_blocks(info.sample_count) {
+                warnings.push(HpsValidationWarning::SampleCountMismatch {
+                    channel,
+                    sample_count: info.sample_count,
+                    decoded_samples,
+                });
+            }
+        }
+
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+
+    /// Compare the [`sample_count`](ChannelInfo::sample_count) in each
+    /// channel's header with the number of samples per channel the blocks
+    /// actually hold. If they disagree by more than the padding in the last
+    /// block, returns the header value and the decoded count of the first
+    /// channel that disagrees.
+    ///
+    /// A mismatch usually means the file was truncated (the header claims more
+    /// samples than there are) or has extra blocks appended to it.
+    pub fn sample_count_mismatch(&self) -> Option<(u32, usize)> {
+        self.channel_info
+            .iter()
+            .take(self.channel_count as usize)
+            .find(|info| !self.sample_count_fits_blocks(info.sample_count))
+            .map(|info| {
+                (
+                    info.sample_count,
+                    self.samples_before_block(self.blocks.len()),
+                )
+            })
+    }
+
+    /// Whether a channel with `sample_count` samples ends within the last block
+    fn sample_count_fits_blocks(&self, sample_count: u32) -> bool {
+        let sample_count = sample_count as usize;
+        sample_count > self.samples_before_block(self.blocks.len().saturating_sub(1))
+            && sample_count <= self.samples_before_block(self.blocks.len())
+    }
+
+    /// Check that every frame header refers to a valid coefficient pair,
+    /// without decoding any audio.
+    ///
+    /// This catches the same [`HpsDecodeError::InvalidCoefficientIndex`] that
+    /// [`decode`](Hps::decode) would, but much more cheaply, which is handy for
+    /// checking lots of files in bulk.
+    pub fn validate_frame_headers(&self) -> Result<(), HpsDecodeError> {
+        let invalid_block = self.blocks.iter().find(|block| {
+            block
+                .frames
+                .iter()
+                .any(|frame| frame.coefficient_index().is_none())
+        });
+        match invalid_block {
+            Some(block) => Err(self.invalid_frame_error(block)),
+            None => Ok(()),
+        }
+    }
+
+    /// Build the error for a block that can't be decoded, pointing at its
+    /// first frame with an invalid coefficient index
+    fn invalid_frame_error(&self, block: &Block) -> HpsDecodeError {
+        let block_index = self
+            .blocks
+            .iter()
+            .position(|b| b.offset == block.offset)
+            .unwrap_or_default();
+        let frame_index = block
+            .frames
+            .iter()
+            .position(|frame| frame.coefficient_index().is_none())
+            .unwrap_or_default();
+        HpsDecodeError::InvalidCoefficientIndex {
+            coefficient_index: block
+                .frames
+                .get(frame_index)
+                .map_or(0, |frame| (frame.header >> 4) as usize),
+            block_index,
+            frame_index,
+            offset: block.offset + BLOCK_HEADER_SIZE + (frame_index * BYTES_PER_FRAME) as u32,
+        }
+    }
+
+    /// Returns `true` if the looping part of the song is the same length for
+    /// every audio channel. If this isn't the case, the channels would drift
+    /// out of sync every time the song loops.
+    ///
+    /// Every block from the loop block on must split its DSP data into the
+    /// same whole number of frames for each channel, and every channel's
+    /// [`sample_count`](ChannelInfo::sample_count), which is where its loop
+    /// ends, must be the same. Songs that don't loop are always considered
+    /// consistent, while songs whose loop block doesn't exist never are.
+    pub fn loop_length_consistent(&self) -> bool {
+        let Some(loop_block_index) = self.loop_block_index else {
+            return true;
+        };
+        let Some(looping_blocks) = self.blocks.get(loop_block_index..) else {
+            return false;
+        };
+        let channel_count = self.channel_count as usize;
+        let channel_info = &self.channel_info[..channel_count];
+
+        let blocks_split_evenly = looping_blocks.iter().all(|block| {
+            block.frames.len().is_multiple_of(channel_count)
+                && block.dsp_data_length as usize == block.frames.len() * BYTES_PER_FRAME
+        });
+        let sample_counts_match = channel_info
+            .iter()
+            .all(|info| info.sample_count == channel_info[0].sample_count);
+        blocks_split_evenly && sample_counts_match
+    }
+
+    /// Returns `true` if re-encoding the decoded audio with each frame's
+    /// original coefficient index and scale reproduces every frame in the file
+    /// byte for byte.
+    ///
+    /// This is useful for testing DSP encoders against real files. See
+    /// [`reencode_mismatched_frames`](Hps::reencode_mismatched_frames) to find
+    /// out how close a file comes to matching.
+    pub fn reencode_matches(&self) -> Result<bool, HpsDecodeError> {
+        Ok(self.reencode_mismatched_frames()? == 0)
+    }
+
+    /// Decode the audio, re-encode it with each frame's original coefficient
+    /// index and scale, and return how many frames differ from the originals.
+    pub fn reencode_mismatched_frames(&self) -> Result<usize, HpsDecodeError> {
+        #[cfg(feature = "parallel")]
+        let blocks = self.blocks.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let blocks = self.blocks.iter();
+
+        blocks
+            .map(|block| {
+                let mut mismatched_frames = 0;
+                let channel_count = self.channel_count as usize;
+
+                for (channel, decoder_state) in
+                    block.decoder_states.iter().take(channel_count).enumerate()
+                {
+                    let frames = block.channel_frames(channel, channel_count);
+                    let coefficients = &self.channel_info[channel].coefficients;
+                    let samples = Self::decode_frames(frames, decoder_state, coefficients)
+                        .ok_or_else(|| self.invalid_frame_error(block))?;
+
+                    let mut hist1 = decoder_state.initial_hist_1;
+                    let mut hist2 = decoder_state.initial_hist_2;
+
+                    for (frame, frame_samples) in
+                        frames.iter().zip(samples.chunks(SAMPLES_PER_FRAME))
+                    {
+                        let reencoded = encode_frame_with_header(
+                            frame_samples,
+                            frame.header,
+                            coefficients,
+                            &mut hist1,
+                            &mut hist2,
+                        );
+                        if reencoded != *frame {
+                            mismatched_frames += 1;
+                        }
+                    }
+                }
+
+                Ok(mismatched_frames)
+            })
+            .sum()
+    }
+
+    /// Returns a hash of the structure of the file, which can be used to tell
+    /// whether two parsed files are laid out identically without comparing
+    /// their audio data.
+    ///
+    /// The hash covers, in order: the sample rate, channel count, every field
+    /// of both [`ChannelInfo`]s, the loop block index, and the `offset`,
+    /// `dsp_data_length` and `next_block_offset` of every block. Frame data and
+    /// decoder states are _not_ included. It's computed with 64-bit FNV-1a over
+    /// the big-endian bytes of each value, so it's stable across platforms and
+    /// versions of this library.
+    pub fn structure_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+
+        hasher.write(&self.sample_rate.to_be_bytes());
+        hasher.write(&self.channel_count.to_be_bytes());
+        for info in &self.channel_info {
+            hasher.write(&info.largest_block_length.to_be_bytes());
+            hasher.write(&info.sample_count.to_be_bytes());
+            for (coef1, coef2) in info.coefficients {
+                hasher.write(&coef1.to_be_bytes());
+                hasher.write(&coef2.to_be_bytes());
+            }
+        }
+
+        // A non-looping file is hashed as a loop block index of u64::MAX
+        let loop_block_index = self.loop_block_index.map_or(u64::MAX, |i| i as u64);
+        hasher.write(&loop_block_index.to_be_bytes());
+
+        for block in &self.blocks {
+            hasher.write(&block.offset.to_be_bytes());
+            hasher.write(&block.dsp_data_length.to_be_bytes());
+            hasher.write(&block.next_block_offset.to_be_bytes());
+        }
+
+        hasher.finish()
+    }
+
+    /// Returns the loop information for this file as a JSON object, for use
+    /// by players that can't parse `.hps` files themselves:
+    ///
+    /// ```json
+    /// {
+    ///   "sample_rate": 32000,
+    ///   "channels": 2,
+    ///   "loop_start_sample": 1234,
+    ///   "total_samples": 5678,
+    ///   "duration_ms": 123456
+    /// }
+    /// ```
+    ///
+    /// Sample positions are per channel, and `loop_start_sample` is `null` if
+    /// the song doesn't loop. `duration_ms` is `null` if the sample rate is 0.
+    pub fn loop_metadata_json(&self) -> String {
+        let total_samples = self.samples_before_block(self.blocks.len());
+        let duration_ms = match (total_samples as u64 * 1000).checked_div(self.sample_rate as u64) {
+            Some(duration_ms) => duration_ms.to_string(),
+            None => "null".to_string(),
+        };
+        let loop_start_sample = match self.loop_block_index {
+            Some(index) => self.samples_before_block(index).to_string(),
+            None => "null".to_string(),
+        };
+
+        format!(
+            r#"{{"sample_rate":{},"channels":{},"loop_start_sample":{},"total_samples":{},"duration_ms":{}}}"#,
+            self.sample_rate, self.channel_count, loop_start_sample, total_samples, duration_ms
+        )
+    }
+
+    /// Returns the indices of blocks that start on a bar boundary, for a song
+    /// at `bpm` beats per minute, in 4/4 time, with loops that are a multiple
+    /// of `bars` bars long. These make musically clean loop points.
+    ///
+    /// Blocks can only start on a frame boundary, so a block counts as
+    /// starting on a bar boundary if it's within one frame of one.
+    pub fn loop_candidates_for_bpm(&self, bpm: f64, bars: u32) -> Vec<usize> {
+        let samples_per_loop = bars as f64 * 4.0 * 60.0 / bpm * self.sample_rate as f64;
+        if !samples_per_loop.is_finite() || samples_per_loop <= 0.0 {
+            return Vec::new();
+        }
+
+        let mut samples_before_block = 0;
+        let mut candidates = Vec::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            let offset = samples_before_block as f64 % samples_per_loop;
+            let distance = offset.min(samples_per_loop - offset);
+            if distance < SAMPLES_PER_FRAME as f64 {
+                candidates.push(index);
+            }
+            samples_before_block += block.channel_sample_count(self.channel_count as usize);
+        }
+        candidates
+    }
+
+    /// Make the song loop back to the start of the block at `index` when it
+    /// reaches the end, or stop it from looping if `index` is `None`. The last
+    /// block is relinked to match, so the change carries over to
+    /// [`decode`](Hps::decode) and [`to_bytes`](Hps::to_bytes).
+    ///
+    /// Returns an error if there is no block at `index`.
+    pub fn set_loop_block(&mut self, index: Option<usize>) -> Result<(), LoopError> {
+        let loop_block_offset = match index {
+            Some(index) => match self.blocks.get(index) {
+                Some(block) => block.offset,
+                None => return Err(LoopError::BlockOutOfRange(index, self.blocks.len())),
+            },
+            None => NO_NEXT_BLOCK,
+        };
+        if let Some(last_block) = self.blocks.last_mut() {
+            last_block.next_block_offset = loop_block_offset;
+        }
+        self.loop_block_index = index;
+        Ok(())
+    }
+
+    /// Stop the song from looping, so it plays through once. This is the same
+    /// as [`set_loop_block(None)`](Hps::set_loop_block).
+    pub fn disable_loop(&mut self) {
+        if let Some(last_block) = self.blocks.last_mut() {
+            last_block.next_block_offset = NO_NEXT_BLOCK;
+        }
+        self.loop_block_index = None;
+    }
+
+    /// Move the loop point to the nearest block boundary within `search`
+    /// samples (per channel) of the current loop point where every channel
+    /// crosses zero, to reduce clicking when the song loops.
+    ///
+    /// Songs can only loop back to the start of a block, so only block
+    /// boundaries are considered. A boundary counts as a zero crossing when the
+    /// last sample of the song and the first sample of the block have opposite
+    /// signs, or either of them is zero, since those are the two samples that
+    /// play back to back when the song loops. If the current loop point is
+    /// already a zero crossing, it is kept. The last block is relinked as with
+    /// [`set_loop_block`](Hps::set_loop_block).
+    ///
+    /// Returns `true` if a zero crossing was found (and the loop point moved to
+    /// it), or `false` if there wasn't one in range or the song doesn't loop.
+    pub fn snap_loop_to_zero_crossing(&mut self, search: usize) -> Result<bool, HpsDecodeError> {
+        let (Some(loop_block_index), Some(last_block)) =
+            (self.loop_block_index, self.blocks.last())
+        else {
+            return Ok(false);
+        };
+        let channel_count = self.channel_count as usize;
+        let loop_start = self.samples_before_block(loop_block_index);
+
+        let last_samples = self.decode_block(last_block)?;
+        if last_samples.len() < channel_count {
+            return Ok(false);
+        }
+        let last_samples = &last_samples[last_samples.len() - channel_count..];
+
+        let mut best_candidate: Option<(usize, usize)> = None;
+        for (index, (block, block_start)) in self.blocks_with_offsets().enumerate() {
+            let distance = (block_start / channel_count).abs_diff(loop_start);
+            if distance > search || best_candidate.is_some_and(|(_, best)| best <= distance) {
+                continue;
+            }
+            if self.block_starts_at_zero_crossing(block, last_samples)? {
+                best_candidate = Some((index, distance));
+            }
+        }
+
+        let Some((index, _)) = best_candidate else {
+            return Ok(false);
+        };
+        Ok(self.set_loop_block(Some(index)).is_ok())
+    }
+
+    /// Returns `true` if every channel crosses zero between its sample in
+    /// `previous_samples` and the first sample of the block
+    fn block_starts_at_zero_crossing(
+        &self,
+        block: &Block,
+        previous_samples: &[i16],
+    ) -> Result<bool, HpsDecodeError> {
+        let channel_count = self.channel_count as usize;
+        for (channel, decoder_state) in block.decoder_states.iter().take(channel_count).enumerate()
+        {
+            let Some(first_frame) = block.channel_frames(channel, channel_count).first() else {
+                return Ok(false);
+            };
+            let first_samples = Self::decode_frames(
+                core::slice::from_ref(first_frame),
+                decoder_state,
+                &self.channel_info[channel].coefficients,
+            )
+            .ok_or_else(|| self.invalid_frame_error(block))?;
+
+            let previous_sample = previous_samples[channel] as i32;
+            let first_sample = first_samples[0] as i32;
+            if previous_sample * first_sample > 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Decode each audio channel, compute a fresh set of decode coefficients
+    /// from the decoded audio, and return how far the stored coefficients are
+    /// from the fresh ones, for each channel.
+    ///
+    /// The drift is the average distance from each stored coefficient pair to
+    /// the closest freshly computed pair, with coefficients scaled to their
+    /// real values (i.e. divided by 2048). A large drift suggests the file was
+    /// encoded with different settings than this crate would use.
+    #[cfg(feature = "std")]
+    pub fn coefficient_drift(&self) -> Result<[f64; 2], HpsDecodeError> {
+        let samples = self.decode_blocks(&self.blocks)?;
+        let channel_count = self.channel_count as usize;
+
+        Ok(core::array::from_fn(|channel| {
+            let channel_samples = samples
+                .iter()
+                .skip(channel.min(channel_count - 1))
+                .step_by(channel_count)
+                .copied()
+                .collect::<Vec<_>>();
+            let fresh = compute_dsp_coefficients(&channel_samples).map(scale_coefficients);
+            let stored = self.channel_info[channel]
+                .coefficients
+                .map(scale_coefficients);
+
+            stored
+                .iter()
+                .map(|&stored_pair| {
+                    fresh
+                        .iter()
+                        .map(|&fresh_pair| predictor_distance(stored_pair, fresh_pair))
+                        .fold(f64::INFINITY, f64::min)
+                })
+                .sum::<f64>()
+                / COEFFICIENT_PAIRS_PER_CHANNEL as f64
+        }))
+    }
+
+    /// Losslessly split a stereo [`Hps`] into two mono ones, one for each
+    /// audio channel. Each holds its channel's frames, coefficients and
+    /// decoder states, with its blocks laid out back to back.
+    ///
+    /// In a mono [`Hps`], both entries of `channel_info` and of each block's
+    /// `decoder_states` hold the same values.
+    ///
+    /// Returns `None` if this [`Hps`] isn't stereo.
+    pub fn split_to_mono(&self) -> Option<[Hps; 2]> {
+        if self.channel_count != 2 {
+            return None;
+        }
+
+        let old_offsets = self.blocks.iter().map(|b| b.offset).collect::<Vec<_>>();
+        let new_indices = (0..self.blocks.len()).collect::<Vec<_>>();
+
+        Some(core::array::from_fn(|channel| {
+            let mut blocks = self
+                .blocks
+                .iter()
+                .map(|block| {
+                    let frames = block.channel_frames(channel, 2).to_vec();
+                    let decoder_state = block.decoder_states[channel].clone();
+                    Block {
+                        offset: block.offset,
+                        dsp_data_length: (frames.len() * BYTES_PER_FRAME) as u32,
+                        next_block_offset: block.next_block_offset,
+                        decoder_states: [decoder_state.clone(), decoder_state],
+                        frames,
+                    }
+                })
+                .collect::<Vec<_>>();
+            lay_out_blocks(&mut blocks, &old_offsets, &new_indices);
+
+            let mut channel_info = self.channel_info[channel].clone();
+            channel_info.largest_block_length =
+                blocks.iter().map(|b| b.dsp_data_length).max().unwrap_or(0);
+
+            Hps {
+                sample_rate: self.sample_rate,
+                channel_count: 1,
+                channel_info: [channel_info.clone(), channel_info],
+                blocks,
+                loop_block_index: self.loop_block_index,
+            }
+        }))
+    }
+
+    /// Returns a copy of the decode coefficients of both channels, so they can
+    /// be copied onto another file with
+    /// [`set_coefficients`](Hps::set_coefficients).
+    pub fn take_coefficients(&self) -> [[(i16, i16); COEFFICIENT_PAIRS_PER_CHANNEL]; 2] {
+        [
+            self.channel_info[0].coefficients,
+            self.channel_info[1].coefficients,
+        ]
+    }
+
+    /// Replace the decode coefficients of both channels. This is useful for
+    /// repairing a file with a damaged header using the coefficients from
+    /// another file made by the same encoder.
+    pub fn set_coefficients(
+        &mut self,
+        coefficients: [[(i16, i16); COEFFICIENT_PAIRS_PER_CHANNEL]; 2],
+    ) {
+        for (info, coefficients) in self.channel_info.iter_mut().zip(coefficients) {
+            info.coefficients = coefficients;
+        }
+    }
+
+    /// Returns a short, human readable overview of the file, e.g.
+    ///
+    /// ```text
+    /// 32000 Hz, 2 channels, 51 blocks, 410592 frames, 89.817s, loops from block 22
+    /// ```
+    ///
+    /// The duration is reported as unknown if the sample rate is 0.
+    pub fn summary(&self) -> String {
+        let frame_count = self.blocks.iter().map(|b| b.frames.len()).sum::<usize>();
+        let samples_per_channel = self.samples_before_block(self.blocks.len()) as u64;
+        let duration = match (samples_per_channel * 1000).checked_div(self.sample_rate as u64) {
+            Some(duration_ms) => format!("{}.{:03}s", duration_ms / 1000, duration_ms % 1000),
+            None => "unknown duration".to_string(),
+        };
+        let loop_status = match self.loop_block_index {
+            Some(index) => format!("loops from block {index}"),
+            None => "doesn't loop".to_string(),
+        };
+
+        format!(
+            "{} Hz, {} channels, {} blocks, {} frames, {}, {}",
+            self.sample_rate,
+            self.channel_count,
+            self.blocks.len(),
+            frame_count,
+            duration,
+            loop_status
+        )
+    }
+
+    /// Returns the number of interleaved samples that [`decode`](Hps::decode)
+    /// will produce, without decoding anything. This is the length of
+    /// [`DecodedHps::samples`], across all audio channels.
+    ///
+    /// Blocks always hold a whole number of 14-sample frames, so this is
+    /// usually a little more than `channel_info[0].sample_count` times the
+    /// channel count, as the last frame is padded. It can also differ by more
+    /// if the file has been edited or truncated without updating its header.
+    pub fn decoded_sample_count(&self) -> usize {
+        self.samples_before_block(self.blocks.len()) * self.channel_count as usize
+    }
+
+    /// Number of samples per channel contained in the blocks before the block
+    /// at `index`
+    pub(crate) fn samples_before_block(&self, index: usize) -> usize {
+        self.blocks[..index]
+            .iter()
+            .map(|block| block.channel_sample_count(self.channel_count as usize))
+            .sum()
+    }
+
+    /// Iterate over the blocks along with the index in the decoded (interleaved)
+    /// samples that each block's audio starts at. This makes it easy to map a
+    /// position in [`DecodedHps::samples`] back to the block it came from.
+    pub fn blocks_with_offsets(&self) -> impl Iterator<Item = (&Block, usize)> {
+        let channel_count = self.channel_count as usize;
+        self.blocks.iter().scan(0, move |sample_index, block| {
+            let block_start = *sample_index;
+            *sample_index += block.channel_sample_count(channel_count) * channel_count;
+            Some((block, block_start))
+        })
+    }
+
+    /// Returns the block that starts at `offset` bytes into the file, if any.
+    pub fn block_at_offset(&self, offset: u32) -> Option<&Block> {
+        self.blocks.iter().find(|block| block.offset == offset)
+    }
+
+    /// Iterate over the blocks in the order they're played, by following each
+    /// block's [`next_block_offset`](Block::next_block_offset) from the first
+    /// block. This is usually the same as the storage order in
+    /// [`blocks`](Hps::blocks), but doesn't have to be.
+    ///
+    /// Iteration ends when a block links back to one that was already visited
+    /// (i.e. the song loops), marks the end of the song, or links to a missing
+    /// block, so each block is yielded at most once.
+    pub fn blocks_in_play_order(&self) -> impl Iterator<Item = &Block> {
+        let mut visited = BTreeSet::new();
+        let mut next = self.blocks.first();
+        core::iter::from_fn(move || {
+            let block = next.filter(|block| visited.insert(block.offset))?;
+            next = self.block_at_offset(block.next_block_offset);
+            Some(block)
+        })
+    }
+
+    /// Merge adjacent blocks together until each block has at least
+    /// `min_frames` frames per channel, which reduces per-block overhead when
+    /// streaming. Block offsets, links, the loop block index and each
+    /// channel's [`largest_block_length`](ChannelInfo::largest_block_length)
+    /// are updated to match.
+    ///
+    /// The decoded audio is guaranteed to stay exactly the same, so blocks are
+    /// only merged when that is possible: the second block's decoder states
+    /// must match the state the decoder is in at the end of the first block,
+    /// and the loop block is never merged into the block before it.
+    pub fn coalesce_blocks(&mut self, min_frames: usize) -> Result<(), HpsDecodeError> {
+        let channel_count = self.channel_count as usize;
+
+        // The decoder history at the end of each block, for each channel
+        #[cfg(feature = "parallel")]
+        let blocks = self.blocks.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let blocks = self.blocks.iter();
+
+        let end_states = blocks
+            .map(|block| {
+                let mut states = [(0, 0); 2];
+                for (channel, state) in states.iter_mut().take(channel_count).enumerate() {
+                    let decoder_state = &block.decoder_states[channel];
+                    let samples = Self::decode_frames(
+                        block.channel_frames(channel, channel_count),
+                        decoder_state,
+                        &self.channel_info[channel].coefficients,
+                    )
+                    .ok_or_else(|| self.invalid_frame_error(block))?;
+                    *state = match samples[..] {
+                        [.., hist2, hist1] => (hist1, hist2),
+                        _ => (decoder_state.initial_hist_1, decoder_state.initial_hist_2),
+                    };
+                }
+                Ok(states)
+            })
+            .collect::<Result<Vec<_>, HpsDecodeError>>()?;
+
+        let old_offsets = self.blocks.iter().map(|b| b.offset).collect::<Vec<_>>();
+        let mut new_indices = Vec::with_capacity(self.blocks.len());
+        let mut blocks: Vec<Block> = Vec::with_capacity(self.blocks.len());
+
+        for (index, block) in core::mem::take(&mut self.blocks).into_iter().enumerate() {
+            if let Some(previous) = blocks.last_mut() {
+                let states_continue = block
+                    .decoder_states
+                    .iter()
+                    .zip(end_states[index - 1])
+                    .take(channel_count)
+                    .all(|(state, (hist1, hist2))| {
+                        state.initial_hist_1 == hist1 && state.initial_hist_2 == hist2
+                    });
+                let can_merge = previous.frames.len() / channel_count < min_frames
+                    && previous.next_block_offset == block.offset
+                    && Some(index) != self.loop_block_index
+                    && states_continue;
+
+                if can_merge {
+                    previous.frames = (0..channel_count)
+                        .flat_map(|c| {
+                            previous
+                                .channel_frames(c, channel_count)
+                                .iter()
+                                .chain(block.channel_frames(c, channel_count))
+                        })
+                        .cloned()
+                        .collect();
+                    previous.dsp_data_length = (previous.frames.len() * BYTES_PER_FRAME) as u32;
+                    previous.next_block_offset = block.next_block_offset;
+                    new_indices.push(blocks.len() - 1);
+                    continue;
+                }
+            }
+            new_indices.push(blocks.len());
+            blocks.push(block);
+        }
+
+        lay_out_blocks(&mut blocks, &old_offsets, &new_indices);
+        let largest_block_length = blocks.iter().map(|b| b.dsp_data_length).max().unwrap_or(0);
+        for info in &mut self.channel_info {
+            info.largest_block_length = largest_block_length;
+        }
+
+        self.loop_block_index = self.loop_block_index.map(|index| new_indices[index]);
+        self.blocks = blocks;
+        Ok(())
+    }
+
+    /// Remove any padding frames from the end of the last block that go beyond
+    /// the [`sample_count`](ChannelInfo::sample_count) of each channel.
+    ///
+    /// Since each frame holds 14 samples, decoding the result produces
+    /// `sample_count` samples per channel rounded up to the nearest multiple
+    /// of 14. Blocks are never removed, so `loop_block_index` stays valid.
+    pub fn with_exact_length(mut self) -> Self {
+        let channel_count = self.channel_count as usize;
+        let frames_needed = self.channel_info[..channel_count]
+            .iter()
+            .map(|info| (info.sample_count as usize).div_ceil(SAMPLES_PER_FRAME))
+            .max()
+            .unwrap_or(0);
+        let frames_present = self.samples_before_block(self.blocks.len()) / SAMPLES_PER_FRAME;
+
+        if let Some(last_block) = self.blocks.last_mut() {
+            let frames_per_channel = last_block.frames.len() / channel_count;
+            // Always leave at least one frame per channel in the block
+            let excess = frames_present
+                .saturating_sub(frames_needed)
+                .min(frames_per_channel.saturating_sub(1));
+
+            if excess > 0 {
+                let frames_to_keep = frames_per_channel - excess;
+                last_block.frames = last_block
+                    .frames
+                    .chunks(frames_per_channel)
+                    .flat_map(|channel_frames| &channel_frames[..frames_to_keep])
+                    .cloned()
+                    .collect();
+                last_block.dsp_data_length = (last_block.frames.len() * BYTES_PER_FRAME) as u32;
+            }
+        }
+
+        self
+    }
+
+    /// Append silent frames to the end of the last block, so that the number of
+    /// frames it holds for each audio channel is a multiple of `multiple`. The
+    /// block's [`dsp_data_length`](Block::dsp_data_length) and each channel's
+    /// [`largest_block_length`](ChannelInfo::largest_block_length) are updated
+    /// to match, while each channel's
+    /// [`sample_count`](ChannelInfo::sample_count) stays the same.
+    ///
+    /// The padding frames are encoded from silence, starting from the decoder
+    /// history at the end of the block. Since ADPCM can't always jump straight
+    /// to zero, the first few padding samples may ramp down from the last
+    /// sample of the song before reaching silence.
+    pub fn pad_final_block(&mut self, multiple: usize) -> Result<(), HpsDecodeError> {
+        let channel_count = self.channel_count as usize;
+        let Some(last_index) = self.blocks.len().checked_sub(1) else {
+            return Ok(());
+        };
+        if multiple == 0 {
+            return Ok(());
+        }
+
+        let last_block = &self.blocks[last_index];
+        let frames_per_channel = last_block.frames.len() / channel_count;
+        let padded_frames_per_channel = frames_per_channel.next_multiple_of(multiple);
+        if padded_frames_per_channel == frames_per_channel {
+            return Ok(());
+        }
+
+        let samples = self.decode_block(last_block)?;
+        let silence = vec![0; (padded_frames_per_channel - frames_per_channel) * SAMPLES_PER_FRAME];
+        let frames = (0..channel_count)
+            .flat_map(|channel| {
+                let mut decoder_state = last_block.decoder_states[channel].clone();
+                let channel_samples = samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channel_count)
+                    .copied()
+                    .collect::<Vec<_>>();
+                if let [.., hist2, hist1] = channel_samples[..] {
+                    decoder_state.initial_hist_1 = hist1;
+                    decoder_state.initial_hist_2 = hist2;
+                }
+                let padding = encode_frames(
+                    &silence,
+                    &self.channel_info[channel].coefficients,
+                    &mut decoder_state,
+                );
+                last_block
+                    .channel_frames(channel, channel_count)
+                    .iter()
+                    .cloned()
+                    .chain(padding)
+            })
+            .collect::<Vec<_>>();
+
+        let last_block = &mut self.blocks[last_index];
+        last_block.frames = frames;
+        last_block.dsp_data_length = (last_block.frames.len() * BYTES_PER_FRAME) as u32;
+
+        let largest_block_length = last_block.dsp_data_length;
+        for info in &mut self.channel_info {
+            info.largest_block_length = largest_block_length.max(info.largest_block_length);
+        }
+        Ok(())
+    }
+
+    /// Returns the minimum number of bytes that the blocks must occupy in order
+    /// to hold every sample listed in [`ChannelInfo::sample_count`], including
+    /// the header of each block.
+    pub fn expected_block_bytes(&self) -> usize {
+        expected_block_bytes(&self.channel_info[..self.channel_count as usize])
+    }
+
+    /// Returns an estimate of how many bytes of memory this [`Hps`] occupies,
+    /// including the heap allocations for its blocks and their frames.
+    pub fn approx_memory_bytes(&self) -> usize {
+        let frame_bytes = self
+            .blocks
+            .iter()
+            .map(|block| block.frames.capacity() * core::mem::size_of::<Frame>())
+            .sum::<usize>();
+
+        core::mem::size_of::<Self>()
+            + self.blocks.capacity() * core::mem::size_of::<Block>()
+            + frame_bytes
+    }
+
+    /// Decode a slice of DSP block frames into samples. Returns `None` if a
+    /// frame has an invalid coefficient index.
+    fn decode_frames(
+        frames: &[Frame],
+        decoder_state: &DSPDecoderState,
+        coefficients: &[(i16, i16)],
+    ) -> Option<Vec<i16>> {
+        let sample_count = frames.len() * SAMPLES_PER_FRAME;
+        let mut samples: Vec<i16> = Vec::with_capacity(sample_count);
+        Self::decode_frames_into(frames, decoder_state, coefficients, &mut samples)?;
+        Some(samples)
+    }
+
+    /// Decode a slice of DSP block frames into samples, appending them to the
+    /// end of `samples`. This lets a buffer be reused across multiple calls,
+    /// such as once per channel of each [`Block`] in a streaming decoder.
+    ///
+    /// Returns `None` if a frame has an invalid coefficient index, in which
+    /// case the samples decoded before that frame are left in `samples`.
+    pub fn decode_frames_into(
+        frames: &[Frame],
+        decoder_state: &DSPDecoderState,
+        coefficients: &[(i16, i16)],
+        samples: &mut Vec<i16>,
+    ) -> Option<()> {
+        samples.reserve(frames.len() * SAMPLES_PER_FRAME);
+
+        let mut hist1 = decoder_state.initial_hist_1;
+        let mut hist2 = decoder_state.initial_hist_2;
+
+        for frame in frames {
+            let scale = 1 << (frame.header & 0xF);
+            let (coef1, coef2) = coefficients[frame.coefficient_index()?];
+
+            #[cfg(not(feature = "simd"))]
+            frame
+                .encoded_sample_data
+                .iter()
+                .flat_map(|&byte| [get_high_nibble(byte), get_low_nibble(byte)])
+                .for_each(|nibble| {
+                    let sample = decode_sample(nibble, scale, coef1, coef2, hist1, hist2);
+
+                    hist2 = hist1;
+                    hist1 = sample;
+                    samples.push(sample);
+                });
+
+            #[cfg(feature = "simd")]
+            {
+                // Only the prediction depends on previously decoded samples, so
+                // unpack and scale all 14 nibbles up front in a form the
+                // compiler can vectorize, then run the sequential part
+                let scaled_nibbles = unpack_scaled_nibbles(&frame.encoded_sample_data, scale);
+                let (coef1, coef2) = (coef1 as i64, coef2 as i64);
+
+                for scaled_nibble in scaled_nibbles {
+                    let prediction = coef1 * hist1 as i64 + coef2 * hist2 as i64;
+                    let sample =
+                        clamp_i16(((scaled_nibble as i64 + 1024 + prediction) >> 11) as i32);
+
+                    hist2 = hist1;
+                    hist1 = sample;
+                    samples.push(sample);
+                }
+            }
+        }
+
+        Some(())
+    }
+}
+
+/// Information about an audio channel. Notably, an audio channel contains 16
+/// "coefficients" that are used in the calculation to decode samples.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelInfo {
+    pub largest_block_length: u32,
+    pub sample_count: u32,
+    pub coefficients: [(i16, i16); COEFFICIENT_PAIRS_PER_CHANNEL],
+    /// The decoder state stored after the coefficients. Always zeroed in
+    /// Melee's files; decoding uses the state of each block instead.
+    pub initial_decoder_state: DSPDecoderState,
+}
+
+/// The audio data contained in an [`Hps`] is split into multiple "blocks", each
+/// containing [`Frame`]s of encoded samples as well as a link to the start of the
+/// next block.
+///
+/// In a stereo [`Hps`], the first half of the frames in each block are for the
+/// left audio channel, and other half are for the right. In a mono [`Hps`],
+/// every frame is for the single audio channel.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Block {
+    pub offset: u32,
+    pub dsp_data_length: u32,
+    pub next_block_offset: u32,
+    pub decoder_states: [DSPDecoderState; 2],
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DSPDecoderState {
+    /// Upper byte of the predictor/scale. Always 0 in Melee's files.
+    pub ps_hi: u8,
+    /// The predictor/scale, matching the header of the channel's first frame
+    /// in the block. [`Hps::to_bytes`] always writes that frame header here,
+    /// so this doesn't need to be updated when frames are changed.
+    pub ps: u8,
+    pub initial_hist_1: i16,
+    pub initial_hist_2: i16,
+    /// The two bytes after the history samples. Always 0 in Melee's files.
+    pub gain: i16,
+}
+
+/// Each frame of audio data contains 14 encoded PCM samples.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Frame {
+    pub header: u8,
+    pub encoded_sample_data: [u8; 7],
+}
+
+impl Block {
+    /// Get the frames in this block that belong to the given audio channel
+    pub(crate) fn channel_frames(&self, channel: usize, channel_count: usize) -> &[Frame] {
+        if channel_count == 1 {
+            return &self.frames;
+        }
+        // The first half of the frames in the block are for the left audio
+        // channel, and the other half are for the right
+        let half_index = self.frames.len() / 2;
+        match channel {
+            0 => &self.frames[..half_index],
+            _ => &self.frames[half_index..],
+        }
+    }
+
+    /// Rearrange frames that alternate between the left and right audio
+    /// channels so that the left channel's frames come first
+    fn deinterleave_frames(&mut self) {
+        let (left_frames, right_frames): (Vec<_>, Vec<_>) = core::mem::take(&mut self.frames)
+            .into_iter()
+            .enumerate()
+            .partition(|(index, _)| index % 2 == 0);
+        self.frames = left_frames
+            .into_iter()
+            .chain(right_frames)
+            .map(|(_, frame)| frame)
+            .collect();
+    }
+
+    /// Number of samples this block decodes to for each audio channel
+    pub(crate) fn channel_sample_count(&self, channel_count: usize) -> usize {
+        self.channel_frames(0, channel_count).len() * SAMPLES_PER_FRAME
+    }
+}
+
+/// Convert a coefficient pair from its 4.11 fixed point format to real values
+#[cfg(feature = "std")]
+fn scale_coefficients((coef1, coef2): (i16, i16)) -> (f64, f64) {
+    (coef1 as f64 / 2048.0, coef2 as f64 / 2048.0)
+}
+
+/// Number of samples per channel that play over the given duration
+pub(crate) fn duration_to_samples(duration: Duration, sample_rate: u32) -> usize {
+    (duration.as_nanos() * sample_rate as u128 / 1_000_000_000) as usize
+}
+
+impl Frame {
+    /// Get the index of the coefficient pair used to decode this frame, or
+    /// `None` if it's out of range
+    pub(crate) fn coefficient_index(&self) -> Option<usize> {
+        let coef_index = (self.header >> 4) as usize;
+        (coef_index < COEFFICIENT_PAIRS_PER_CHANNEL).then_some(coef_index)
+    }
+}
+
+/// Lay `blocks` out back to back, and point their links at the new offsets.
+///
+/// `old_offsets` holds the offset of every block before any were merged or
+/// resized, and `new_indices` maps each of them to its index in `blocks`.
+fn lay_out_blocks(blocks: &mut [Block], old_offsets: &[u32], new_indices: &[usize]) {
+    let new_offsets = blocks
+        .iter()
+        .scan(DSP_BLOCK_SECTION_OFFSET, |offset, block| {
+            let block_offset = *offset;
+            *offset += BLOCK_HEADER_SIZE + block.dsp_data_length;
+            Some(block_offset)
+        })
+        .collect::<Vec<_>>();
+    for (block, &new_offset) in blocks.iter_mut().zip(&new_offsets) {
+        block.offset = new_offset;
+        if let Some(old_index) = old_offsets
+            .iter()
+            .position(|&o| o == block.next_block_offset)
+        {
+            block.next_block_offset = new_offsets[new_indices[old_index]];
+        }
+    }
+}
+
+/// Offsets of every block that is either the first block, or linked to by
+/// another block
+fn referenced_block_offsets(blocks: &[Block]) -> BTreeSet<u32> {
+    core::iter::once(DSP_BLOCK_SECTION_OFFSET)
+        .chain(blocks.iter().map(|b| b.next_block_offset))
+        .collect()
+}
+
+/// Number of bytes of blocks needed to hold each channel's samples, counting
+/// the header of the fewest blocks that could hold them. Blocks are assumed to
+/// be no larger than the largest block listed in the channel info.
+fn expected_block_bytes(channel_info: &[ChannelInfo]) -> usize {
+    let frame_counts = channel_info
+        .iter()
+        .map(|info| (info.sample_count as usize).div_ceil(SAMPLES_PER_FRAME));
+    let largest_block_length = channel_info
+        .iter()
+        .map(|info| info.largest_block_length as usize)
+        .max()
+        .unwrap_or_default();
+    let frames_per_block = largest_block_length / BYTES_PER_FRAME / channel_info.len().max(1);
+    let block_count = match frames_per_block {
+        // Without a block size, there's no telling how many headers there are
+        0 => 0,
+        _ => frame_counts
+            .clone()
+            .max()
+            .unwrap_or_default()
+            .div_ceil(frames_per_block),
+    };
+    block_count * BLOCK_HEADER_SIZE as usize + frame_counts.sum::<usize>() * BYTES_PER_FRAME
+}
+
+#[cfg(not(feature = "simd"))]
+static NIBBLE_TO_I8: [i8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, -8, -7, -6, -5, -4, -3, -2, -1];
+
+/// Decode a single nibble into a PCM sample, given the two previously decoded
+/// samples
+#[cfg(not(feature = "simd"))]
+#[inline(always)]
+pub(crate) fn decode_sample(
+    nibble: i8,
+    scale: i32,
+    coef1: i16,
+    coef2: i16,
+    hist1: i16,
+    hist2: i16,
+) -> i16 {
+    clamp_i16(decode_sample_unclamped(
+        nibble, scale, coef1, coef2, hist1, hist2,
+    ))
+}
+
+/// Same as [`decode_sample`], but without clamping the result to the range of
+/// an `i16`
+///
+/// The prediction is calculated with 64-bit integers, since two large
+/// coefficients (as found in files with a damaged header) can overflow an
+/// `i32` before the result is scaled back down.
+#[inline(always)]
+pub(crate) fn decode_sample_unclamped(
+    nibble: i8,
+    scale: i32,
+    coef1: i16,
+    coef2: i16,
+    hist1: i16,
+    hist2: i16,
+) -> i32 {
+    ((((nibble as i64 * scale as i64) << 11)
+        + 1024
+        + (coef1 as i64 * hist1 as i64 + coef2 as i64 * hist2 as i64))
+        >> 11) as i32
+}
+
+/// Unpack the 14 nibbles in a frame's encoded sample data and apply `scale` to
+/// each of them, producing `(nibble * scale) << 11` for every sample
+#[cfg(feature = "simd")]
+#[inline(always)]
+fn unpack_scaled_nibbles(encoded_sample_data: &[u8; 7], scale: i32) -> [i32; SAMPLES_PER_FRAME] {
+    let mut scaled_nibbles = [0; SAMPLES_PER_FRAME];
+    for (pair, &byte) in scaled_nibbles.chunks_exact_mut(2).zip(encoded_sample_data) {
+        // Shifting the nibble into the top of an i8 and back down sign-extends
+        // it without needing a lookup table
+        pair[0] = ((byte as i8) >> 4) as i32;
+        pair[1] = (((byte << 4) as i8) >> 4) as i32;
+    }
+    scaled_nibbles.map(|nibble| (nibble * scale) << 11)
+}
+
+#[cfg(not(feature = "simd"))]
+#[inline(always)]
+fn get_low_nibble(byte: u8) -> i8 {
+    NIBBLE_TO_I8[(byte & 0xF) as usize]
+}
+
+#[cfg(not(feature = "simd"))]
+#[inline(always)]
+fn get_high_nibble(byte: u8) -> i8 {
+    NIBBLE_TO_I8[((byte >> 4) & 0xF) as usize]
+}
+
+#[inline(always)]
+pub(crate) fn clamp_i16(val: i32) -> i16 {
+    if val < (i16::MIN as i32) {
+        i16::MIN
+    } else if val > (i16::MAX as i32) {
+        i16::MAX
+    } else {
+        val as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::encoder::HpsBuilder;
+    #[cfg(feature = "std")]
+    use crate::errors::BuildError;
+
+    #[test]
+    fn decodes_blocks_correctly() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let decoded_bytes = hps
+            .decode()
+            .unwrap()
+            .samples()
+            .iter()
+            .flat_map(|sample| sample.to_be_bytes())
+            .collect::<Vec<_>>();
+
+        // // Create a new binary file of decoded samples for testing
+        // use std::io::prelude::*;
+        // let mut file = std::fs::File::create("new.bin").unwrap();
+        // file.write_all(&decoded_bytes).unwrap();
+
+        let expected_bytes = std::fs::read("test-data/test-song-decoded.bin").unwrap();
+        assert_eq!(expected_bytes, decoded_bytes);
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn matches_reference_decode() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let decoded = hps.decode().unwrap();
+
+        let mut expected_bytes = std::fs::read("test-data/test-song-decoded.bin").unwrap();
+        assert!(decoded.matches_reference_be(&expected_bytes));
+
+        expected_bytes[1] ^= 1;
+        assert!(!decoded.matches_reference_be(&expected_bytes));
+        assert!(!decoded.matches_reference_be(&expected_bytes[2..]));
+    }
+
+    #[test]
+    fn parses_interleaved_block_layout() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let hps: Hps = bytes.as_slice().try_into().unwrap();
+
+        // Rewrite every block so that its frames alternate between channels
+        let mut interleaved_bytes = bytes.clone();
+        for block in &hps.blocks {
+            let frame_data_start = (block.offset + BLOCK_HEADER_SIZE) as usize;
+            let half_index = block.frames.len() / 2;
+            for index in 0..half_index {
+                for (channel, original_index) in [index, half_index + index].into_iter().enumerate()
+                {
+                    let from = frame_data_start + original_index * BYTES_PER_FRAME;
+                    let to = frame_data_start + (index * 2 + channel) * BYTES_PER_FRAME;
+                    interleaved_bytes[to..to + BYTES_PER_FRAME]
+                        .copy_from_slice(&bytes[from..from + BYTES_PER_FRAME]);
+                }
+            }
+        }
+
+        let interleaved_hps =
+            Hps::parse_with_layout(&interleaved_bytes, BlockLayout::Interleaved).unwrap();
+        assert_eq!(interleaved_hps, hps);
+    }
+
+    #[test]
+    fn parses_hps_embedded_at_an_offset() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let hps: Hps = bytes.as_slice().try_into().unwrap();
+
+        let container = [vec![0xAB; 100], bytes, vec![0xCD; 100]].concat();
+        assert_eq!(Hps::try_from_at(&container, 100).unwrap(), hps);
+        assert!(matches!(
+            Hps::try_from_at(&container, 99),
+            Err(HpsParseError::InvalidMagicNumber)
+        ));
+        assert!(matches!(
+            Hps::try_from_at(&container, container.len() + 1),
+            Err(HpsParseError::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn reports_parse_progress() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let mut reports = Vec::new();
+        Hps::try_from_with_progress(&bytes, |fraction| reports.push(fraction)).unwrap();
+
+        assert_eq!(reports.len(), 52);
+        assert!(reports.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(reports.last(), Some(&1.0));
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        for path in [
+            "test-data/test-song.hps",
+            "test-data/short-last-block-with-loop.hps",
+        ] {
+            let hps: Hps = std::fs::read(path).unwrap().try_into().unwrap();
+            assert_eq!(Hps::try_from(hps.to_bytes()).unwrap(), hps);
+        }
+    }
+
+    #[test]
+    fn exposes_raw_decoder_state_fields() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        for block in &hps.blocks {
+            for (channel, state) in block.decoder_states.iter().enumerate() {
+                assert_eq!(state.ps_hi, 0);
+                assert_eq!(state.ps, block.channel_frames(channel, 2)[0].header);
+                assert_eq!(state.gain, 0);
+            }
+        }
+
+        // A stale predictor/scale is rewritten from the first frame header
+        let mut stale = hps.clone();
+        stale.blocks[3].decoder_states[1].ps = 0x77;
+        assert_eq!(Hps::try_from(stale.to_bytes()).unwrap(), hps);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn encodes_pcm() {
+        // A couple of seconds of chords, looping from the one second mark
+        let samples = (0..64_000)
+            .flat_map(|n| {
+                let t = n as f64 / 32_000.0;
+                let left = (t * 440.0 * std::f64::consts::TAU).sin()
+                    + (t * 554.4 * std::f64::consts::TAU).sin();
+                let right = (t * 329.6 * std::f64::consts::TAU).sin();
+                [(left * 8_000.0) as i16, (right * 8_000.0) as i16]
+            })
+            .collect::<Vec<_>>();
+
+        let hps = Hps::from_pcm(&samples, 32_000, 2, Some(32_000)).unwrap();
+        let parsed = Hps::try_from(hps.to_bytes()).unwrap();
+        assert_eq!(parsed, hps);
+
+        // The loop point is rounded down to the start of a frame
+        let loop_block_index = parsed.loop_block_index.unwrap();
+        assert_eq!(parsed.samples_before_block(loop_block_index), 31_990);
+
+        let decoded = parsed.decode().unwrap();
+        let (signal, noise) = samples.iter().zip(decoded.samples()).fold(
+            (0.0, 0.0),
+            |(signal, noise), (&original, &decoded)| {
+                let error = original as f64 - decoded as f64;
+                (signal + (original as f64).powi(2), noise + error * error)
+            },
+        );
+        let snr = 10.0 * (signal / noise).log10();
+        assert!(snr > 40.0);
+    }
+
+    #[test]
+    fn encoder_tracks_saturating_history() {
+        // A square wave at full scale makes the prediction overshoot, so
+        // decoded samples get clamped
+        let samples = (0..14 * 40)
+            .map(|n| if n / 21 % 2 == 0 { i16::MAX } else { i16::MIN })
+            .chain([1, 2, 3])
+            .collect::<Vec<_>>();
+        let coefficients = [(4096, -2048), (2048, 0), (0, 0), (1024, 512)]
+            .into_iter()
+            .chain([(0, 0); 4])
+            .collect::<Vec<_>>();
+
+        let mut decoder_state = DSPDecoderState::default();
+        let frames = encode_frames(&samples, &coefficients, &mut decoder_state);
+        assert_eq!(frames.len(), 41);
+
+        let decoded =
+            Hps::decode_frames(&frames, &DSPDecoderState::default(), &coefficients).unwrap();
+        let [.., hist2, hist1] = decoded[..] else {
+            unreachable!()
+        };
+        assert_eq!(decoder_state.initial_hist_1, hist1);
+        assert_eq!(decoder_state.initial_hist_2, hist2);
+        assert!(decoded[..14 * 40]
+            .iter()
+            .zip(&samples)
+            .all(|(&decoded, &sample)| (decoded as i32 - sample as i32).abs() < 4096));
+    }
+
+    #[test]
+    fn parses_mono_files() {
+        let stereo: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let [left, _] = stereo.split_to_mono().unwrap();
+
+        let mono = Hps::try_from(left.to_bytes()).unwrap();
+        assert_eq!(mono.channel_count, 1);
+        assert_eq!(mono, left);
+
+        let decoded = mono.decode().unwrap();
+        assert_eq!(decoded.channel_count, 1);
+        assert_eq!(decoded.samples().len(), 384_048);
+    }
+
+    #[test]
+    fn doesnt_include_any_blocks_more_than_once() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let block_count = hps.blocks.len();
+        let unique_block_offsets = hps
+            .blocks
+            .iter()
+            .map(|block| block.offset)
+            .collect::<BTreeSet<_>>();
+        let unique_block_count = unique_block_offsets.len();
+        assert_eq!(block_count, unique_block_count);
+    }
+
+    #[test]
+    fn checks_loop_length_consistency() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(hps.loop_length_consistent());
+
+        // The right channel gets an extra frame in a looping block
+        let mut uneven_block = hps.clone();
+        uneven_block.blocks[30].frames.pop();
+        uneven_block.blocks[30].dsp_data_length -= 8;
+        assert!(!uneven_block.loop_length_consistent());
+
+        // Blocks before the loop don't matter
+        let mut uneven_intro = hps.clone();
+        uneven_intro.blocks[5].frames.pop();
+        uneven_intro.blocks[5].dsp_data_length -= 8;
+        assert!(uneven_intro.loop_length_consistent());
+
+        // The DSP data splits partway through a frame
+        let mut partial_frame = hps.clone();
+        partial_frame.blocks[30].dsp_data_length -= 4;
+        assert!(!partial_frame.loop_length_consistent());
+
+        // The loop ends at a different sample in each channel
+        let mut uneven_sample_counts = hps.clone();
+        uneven_sample_counts.channel_info[1].sample_count -= 14;
+        assert!(!uneven_sample_counts.loop_length_consistent());
+
+        let mut missing_loop_block = hps.clone();
+        missing_loop_block.loop_block_index = Some(hps.blocks.len() + 1);
+        assert!(!missing_loop_block.loop_length_consistent());
+
+        let mut non_looping = uneven_block.clone();
+        non_looping.disable_loop();
+        assert!(non_looping.loop_length_consistent());
+    }
+
+    #[test]
+    fn pads_final_block_with_silence() {
+        let mut hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let original = hps.clone();
+        let decoded = hps.decode().unwrap();
+        let last_frames = hps.blocks.last().unwrap().frames.len() / 2;
+
+        hps.pad_final_block(last_frames + 10).unwrap();
+        let last_block = hps.blocks.last().unwrap();
+        assert_eq!(last_block.frames.len(), (last_frames + 10) * 2);
+        assert_eq!(
+            last_block.dsp_data_length as usize,
+            (last_frames + 10) * 2 * 8
+        );
+        assert_eq!(
+            hps.channel_info[0].sample_count,
+            original.channel_info[0].sample_count
+        );
+        assert_eq!(hps.validate(), Ok(()));
+        assert_eq!(Hps::try_from(hps.to_bytes()).unwrap(), hps);
+
+        let padded = hps.decode().unwrap();
+        let padding = &padded.samples()[decoded.samples().len()..];
+        assert_eq!(
+            padded.samples()[..decoded.samples().len()],
+            *decoded.samples()
+        );
+        assert_eq!(padding.len(), 10 * SAMPLES_PER_FRAME * 2);
+        assert!(padding.iter().all(|&s| s == 0));
+
+        // Padding already aligned blocks does nothing
+        let padded_hps = hps.clone();
+        hps.pad_final_block(last_frames + 10).unwrap();
+        hps.pad_final_block(0).unwrap();
+        assert_eq!(hps, padded_hps);
+
+        // When the song doesn't end in silence, the padding settles on it
+        // within a frame
+        let mut loud_ending = original.clone();
+        loud_ending.blocks.truncate(12);
+        let last_block = loud_ending.decode_block(&loud_ending.blocks[11]).unwrap();
+        assert!(last_block.iter().rev().take(2).all(|s| s.abs() > 9_000));
+
+        loud_ending.pad_final_block(last_frames + 10).unwrap();
+        let padded_block = loud_ending.decode_block(&loud_ending.blocks[11]).unwrap();
+        let (first_frame, rest) = padded_block[last_block.len()..].split_at(SAMPLES_PER_FRAME * 2);
+        assert!(first_frame.iter().all(|s| s.abs() < 512));
+        assert!(rest.iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn keeps_unreferenced_blocks_on_request() {
+        let bytes = std::fs::read("test-data/short-last-block-with-loop.hps").unwrap();
+        let hps = Hps::try_from(bytes.as_slice()).unwrap();
+        let loop_block_offset = hps.blocks[hps.loop_block_index.unwrap()].offset;
+
+        // A block holding one silent frame per channel, appended to the file
+        let orphan_block = |next_block_offset: u32| {
+            let mut block = 16u32.to_be_bytes().to_vec();
+            block.extend([0; 4]);
+            block.extend(next_block_offset.to_be_bytes());
+            block.extend([0; 20]); // Decoder states and padding
+            block.extend([0; 16]); // Frames
+            block
+        };
+
+        // A block that nothing links to is discarded unless asked to keep it
+        let mut with_orphan = bytes.clone();
+        with_orphan.extend(orphan_block(NO_NEXT_BLOCK));
+        assert_eq!(Hps::try_from(with_orphan.as_slice()).unwrap(), hps);
+
+        let kept = Hps::try_from_keep_all(&with_orphan).unwrap();
+        assert_eq!(kept.blocks.len(), 9);
+        assert_eq!(kept.blocks[..8], hps.blocks);
+        assert_eq!(kept.blocks[8].offset as usize, bytes.len());
+        assert_eq!(kept.loop_block_index, hps.loop_block_index);
+        assert_eq!(kept.unreferenced_blocks(), vec![8]);
+
+        // A block that only an unreferenced block links to survives a normal
+        // parse, but is then unreferenced itself
+        let mut with_chain = bytes.clone();
+        with_chain.extend(orphan_block(bytes.len() as u32 + 0x30));
+        with_chain.extend(orphan_block(loop_block_offset));
+        let parsed = Hps::try_from(with_chain.as_slice()).unwrap();
+        assert_eq!(parsed.blocks.len(), 9);
+        assert_eq!(parsed.blocks[8].offset as usize, bytes.len() + 0x30);
+        assert_eq!(parsed.unreferenced_blocks(), vec![8]);
+
+        let kept = Hps::try_from_keep_all(&with_chain).unwrap();
+        assert_eq!(kept.unreferenced_blocks(), vec![8]);
+        assert_eq!(kept.blocks.len(), 10);
+    }
+
+    #[test]
+    fn returns_encoded_bytes_for_ranges() {
+        let bytes = std::fs::read("test-data/short-last-block-with-loop.hps").unwrap();
+        let hps = Hps::try_from(bytes.as_slice()).unwrap();
+
+        // The encoded frames of one channel of a block, straight from the file
+        let channel_bytes = |block: &Block, channel: usize| {
+            let channel_len = block.frames.len() / 2 * BYTES_PER_FRAME;
+            let start = (block.offset + BLOCK_HEADER_SIZE) as usize + channel * channel_len;
+            &bytes[start..start + channel_len]
+        };
+        let [first_block, second_block, ..] = &hps.blocks[..] else {
+            unreachable!()
+        };
+        let boundary = first_block.frames.len() / 2 * SAMPLES_PER_FRAME;
+
+        assert_eq!(
+            hps.encoded_bytes_for_range(0, 0, boundary),
+            channel_bytes(first_block, 0)
+        );
+        assert_eq!(
+            hps.encoded_bytes_for_range(1, boundary, boundary + SAMPLES_PER_FRAME),
+            channel_bytes(second_block, 1)[..BYTES_PER_FRAME]
+        );
+
+        // A range crossing a block boundary covers the frames either side of it
+        let first_block_right = channel_bytes(first_block, 1);
+        let expected = [
+            &first_block_right[first_block_right.len() - BYTES_PER_FRAME..],
+            &channel_bytes(second_block, 1)[..BYTES_PER_FRAME],
+        ]
+        .concat();
+        assert_eq!(
+            hps.encoded_bytes_for_range(1, boundary - 1, boundary + 1),
+            expected
+        );
+
+        assert!(hps.encoded_bytes_for_range(2, 0, boundary).is_empty());
+    }
+
+    #[test]
+    fn parses_last_block_even_if_its_very_short() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        assert_eq!(hps.blocks.len(), 8);
+        assert!(hps.loop_block_index.is_some());
+    }
+
+    #[test]
+    fn properly_handles_invalid_coefficient_index() {
+        let hps: Hps = std::fs::read("test-data/corrupt-dsp-frame-header.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        assert!(matches!(
+            hps.decode().unwrap_err(),
+            HpsDecodeError::InvalidCoefficientIndex {
+                coefficient_index: 8,
+                block_index: 0,
+                frame_index: 0,
+                offset: 0xa0,
+            }
+        ));
+        assert!(matches!(
+            hps.validate_frame_headers().unwrap_err(),
+            HpsDecodeError::InvalidCoefficientIndex { offset: 0xa0, .. }
+        ));
+
+        // Corrupt one of the right channel's frames partway through the song
+        let mut hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let frame_index = hps.blocks[3].frames.len() / 2 + 5;
+        hps.blocks[3].frames[frame_index].header = 0xf0;
+        let expected_offset = hps.blocks[3].offset + 0x20 + frame_index as u32 * 8;
+
+        for error in [
+            hps.decode().unwrap_err(),
+            hps.validate_frame_headers().unwrap_err(),
+        ] {
+            let HpsDecodeError::InvalidCoefficientIndex {
+                coefficient_index,
+                block_index,
+                frame_index: error_frame_index,
+                offset,
+            } = error;
+            assert_eq!(coefficient_index, 15);
+            assert_eq!(block_index, 3);
+            assert_eq!(error_frame_index, frame_index);
+            assert_eq!(offset, expected_offset);
+        }
+    }
+
+    #[test]
     fn reads_metadata_correctly() {
         let hps: Hps = std::fs::read("test-data/test-song.hps")
             .unwrap()
             .try_into()
             .unwrap();
-        assert_eq!(hps.sample_rate, 32000);
-        assert_eq!(hps.channel_count, 2);
+        assert_eq!(hps.sample_rate, 32000);
+        assert_eq!(hps.channel_count, 2);
+        assert_eq!(
+            hps.channel_info[0],
+            ChannelInfo {
+                largest_block_length: 65536,
+                sample_count: 2874134,
+                coefficients: [
+                    (492, -294),
+                    (2389, -1166),
+                    (1300, 135),
+                    (3015, -1133),
+                    (1491, -717),
+                    (2845, -1208),
+                    (1852, -11),
+                    (3692, -1705),
+                ],
+                initial_decoder_state: DSPDecoderState::default(),
+            },
+        );
+        assert_eq!(
+            hps.channel_info[1],
+            ChannelInfo {
+                largest_block_length: 65536,
+                sample_count: 2874134,
+                coefficients: [
+                    (411, -287),
+                    (2359, -1100),
+                    (1247, 143),
+                    (3147, -1288),
+                    (1472, -773),
+                    (2600, -894),
+                    (1745, 93),
+                    (3703, -1715),
+                ],
+                initial_decoder_state: DSPDecoderState::default(),
+            }
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn parses_compressed_files() {
+        use std::io::Write;
+
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let hps = Hps::try_from(bytes.as_slice()).unwrap();
+
+        let zstd_bytes = zstd::encode_all(bytes.as_slice(), 0).unwrap();
+        let mut gzip_encoder =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip_encoder.write_all(&bytes).unwrap();
+        let gzip_bytes = gzip_encoder.finish().unwrap();
+
+        assert_eq!(
+            Hps::from_compressed(zstd_bytes.as_slice(), Codec::Zstd).unwrap(),
+            hps
+        );
+        assert_eq!(
+            Hps::from_compressed(gzip_bytes.as_slice(), Codec::Gzip).unwrap(),
+            hps
+        );
+    }
+
+    #[test]
+    fn parses_any_container_of_bytes() {
+        let bytes = std::fs::read("test-data/short-last-block-with-loop.hps").unwrap();
+        let expected = Hps::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(Hps::parse(bytes.as_slice()).unwrap(), expected);
+        assert_eq!(Hps::parse(&bytes).unwrap(), expected);
+        assert_eq!(Hps::parse(bytes.clone()).unwrap(), expected);
+        assert_eq!(
+            Hps::parse(bytes.clone().into_boxed_slice()).unwrap(),
+            expected
+        );
+        assert!(matches!(
+            Hps::parse(b"hello world"),
+            Err(HpsParseError::InvalidMagicNumber)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn parses_from_readers() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let hps: Hps = bytes.as_slice().try_into().unwrap();
+
+        let file = std::fs::File::open("test-data/test-song.hps").unwrap();
+        assert_eq!(Hps::from_reader(file).unwrap(), hps);
+
+        for length in [0, 4, 8, 0x40, 0x7f] {
+            assert!(matches!(
+                Hps::from_reader(&bytes[..length]),
+                Err(HpsError::Parse(HpsParseError::Incomplete(winnow::error::Needed::Size(needed))))
+                    if needed.get() == 0x80 - length
+            ));
+        }
+
+        let error = Hps::from_reader(&b"HALPST"[..]).unwrap_err();
+        assert!(matches!(
+            error,
+            HpsError::Parse(HpsParseError::InvalidMagicNumber)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn parses_from_paths() {
+        let hps = Hps::try_from_path("test-data/test-song.hps").unwrap();
+        assert_eq!(
+            hps,
+            Hps::from_reader(std::fs::File::open("test-data/test-song.hps").unwrap()).unwrap()
+        );
+
+        assert!(matches!(
+            Hps::try_from_path("test-data/missing.hps"),
+            Err(HpsError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+        assert!(matches!(
+            Hps::try_from_path("Cargo.toml"),
+            Err(HpsError::Parse(HpsParseError::InvalidMagicNumber))
+        ));
+    }
+
+    #[test]
+    fn counts_decoded_samples_without_decoding() {
+        for (path, padding) in [
+            ("test-data/test-song.hps", 10),
+            ("test-data/short-last-block-with-loop.hps", 48),
+        ] {
+            let hps: Hps = std::fs::read(path).unwrap().try_into().unwrap();
+            let sample_count = hps.channel_info[0].sample_count as usize;
+            assert_eq!(
+                hps.decoded_sample_count(),
+                hps.decode().unwrap().samples().len()
+            );
+            assert_eq!(hps.decoded_sample_count(), (sample_count + padding) * 2);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decodes_many_files_at_once() {
+        let paths = [
+            "test-data/test-song.hps",
+            "test-data/corrupt-dsp-frame-header.hps",
+            "test-data/short-last-block-with-loop.hps",
+        ];
+        let items = paths.map(|path| Hps::try_from_path(path).unwrap());
+
+        let results = Hps::decode_many(items.clone());
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &items[0].decode().unwrap());
+        assert!(matches!(
+            results[1],
+            Err(HpsDecodeError::InvalidCoefficientIndex { .. })
+        ));
+        assert_eq!(results[2].as_ref().unwrap(), &items[2].decode().unwrap());
+
+        assert!(Hps::decode_many([]).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decodes_from_paths() {
+        let audio = crate::from_path("test-data/short-last-block-with-loop.hps").unwrap();
+        assert_eq!(audio.samples().len(), 384_048 * 2);
+
+        assert!(matches!(
+            crate::from_path("test-data/corrupt-dsp-frame-header.hps"),
+            Err(HpsError::Decode(
+                HpsDecodeError::InvalidCoefficientIndex { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn detects_truncated_files() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let hps = Hps::try_from(bytes.as_slice()).unwrap();
+        assert!(bytes.len() >= 0x80 + hps.expected_block_bytes());
+
+        assert!(Hps::try_from_strict(bytes.as_slice()).is_ok());
+
+        let error = Hps::try_from_strict(&bytes[..bytes.len() / 2]).unwrap_err();
+        assert!(matches!(error, HpsParseError::Truncated { .. }));
+    }
+
+    #[test]
+    fn detects_files_cut_short_by_a_few_bytes() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let cut = &bytes[..bytes.len() - 4];
+
+        assert!(matches!(
+            Hps::try_from_strict(cut),
+            Err(HpsParseError::Truncated { expected, actual })
+                if expected == bytes.len() && actual == cut.len()
+        ));
+
+        // A normal parse drops the partial block, which validation then reports
+        let hps = Hps::try_from(cut).unwrap();
+        assert_eq!(hps.blocks.len(), 50);
+        assert!(hps.sample_count_mismatch().is_some());
+        assert!(hps
+            .validate()
+            .unwrap_err()
+            .iter()
+            .any(|warning| matches!(warning, HpsValidationWarning::SampleCountMismatch { .. })));
+
+        // A mono file whose blocks each hold twice as many frames as a stereo
+        // block's channel is complete, and only needs half the headers
+        let stereo = Hps::try_from(bytes).unwrap();
+        let [mut mono, _] = stereo.split_to_mono().unwrap();
+        mono.coalesce_blocks(MAX_FRAMES_PER_BLOCK * 2).unwrap();
+        assert!(mono.blocks.len() < stereo.blocks.len());
+        let mono_bytes = mono.to_bytes();
+        assert_eq!(mono_bytes.len(), 0x80 + mono.expected_block_bytes());
+        assert_eq!(Hps::try_from_strict(mono_bytes.as_slice()).unwrap(), mono);
+        assert!(matches!(
+            Hps::try_from_strict(&mono_bytes[..mono_bytes.len() - 4]),
+            Err(HpsParseError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn reencodes_frames_exactly() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(hps.reencode_mismatched_frames().unwrap(), 0);
+        assert!(hps.reencode_matches().unwrap());
+    }
+
+    #[test]
+    fn decodes_ranges_to_the_exact_sample() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let samples = hps.decode().unwrap().samples().to_vec();
+
+        // 1.5s to 4.25s, which starts and ends partway through a block
+        let range = hps
+            .decode_range(Duration::from_millis(1500), Duration::from_millis(4250))
+            .unwrap();
+        assert!(!range.is_looping());
+        assert_eq!(range.samples(), &samples[48_000 * 2..136_000 * 2]);
+
+        // Entirely within the first block
+        let range = hps
+            .decode_range(Duration::from_millis(500), Duration::from_millis(510))
+            .unwrap();
+        assert_eq!(range.samples(), &samples[16_000 * 2..16_320 * 2]);
+
+        // Windows are clamped to the end of the song
+        let range = hps
+            .decode_range(Duration::from_secs(89), Duration::from_secs(120))
+            .unwrap();
+        assert_eq!(range.samples(), &samples[89 * 32_000 * 2..]);
+        let range = hps
+            .decode_range(Duration::from_secs(100), Duration::from_secs(120))
+            .unwrap();
+        assert!(range.samples().is_empty());
+    }
+
+    #[test]
+    fn finds_loop_candidates_for_bpm() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        // Pick a tempo where 2 bars line up exactly with the loop block
+        let loop_start = hps.samples_before_block(22) as f64;
+        let bpm = 2.0 * 4.0 * 60.0 * hps.sample_rate as f64 / loop_start;
+
+        let candidates = hps.loop_candidates_for_bpm(bpm, 2);
+        assert!(candidates.contains(&0));
+        assert!(candidates.contains(&22));
+        assert!(!candidates.contains(&21));
+        assert!(hps.loop_candidates_for_bpm(0.0, 2).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn computes_coefficients_close_to_stored_ones() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let drift = hps.coefficient_drift().unwrap();
+        assert!(drift.iter().all(|&d| (0.0..0.01).contains(&d)));
+
+        // Re-deriving the coefficients from the decoded audio lands on nearly
+        // the same pairs, in the same order, as Nintendo's encoder
+        let left = hps.decode().unwrap().channel_samples(0).unwrap();
+        let fresh = compute_dsp_coefficients(&left[..hps.channel_info[0].sample_count as usize]);
+        for (stored, fresh) in hps.channel_info[0].coefficients.iter().zip(fresh) {
+            assert!((stored.0 - fresh.0).abs() < 32 && (stored.1 - fresh.1).abs() < 32);
+        }
+
+        // A pure tone is predicted exactly by (2cos(w), -1), so the other
+        // groups are left empty
+        let w = 0.05f64;
+        let tone = (0..14_000)
+            .map(|n| ((n as f64 * w).sin() * 10_000.0) as i16)
+            .collect::<Vec<_>>();
+        let coefficients = compute_dsp_coefficients(&tone);
+        assert_eq!(coefficients[0], ((2.0 * w.cos() * 2048.0) as i16, -2048));
+        assert!(coefficients[1..].iter().all(|&pair| pair == (0, 0)));
+    }
+
+    #[test]
+    fn splits_stereo_into_mono() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let stereo_samples = hps.decode().unwrap().samples().to_vec();
+
+        let split = hps.split_to_mono().unwrap();
+        for (channel, mono) in split.iter().enumerate() {
+            assert_eq!(mono.channel_count, 1);
+            assert_eq!(mono.loop_block_index, hps.loop_block_index);
+            let expected = stereo_samples
+                .iter()
+                .skip(channel)
+                .step_by(2)
+                .copied()
+                .collect::<Vec<_>>();
+            assert_eq!(mono.decode().unwrap().samples(), expected);
+
+            // A mono file has nothing left to split
+            assert_eq!(mono.split_to_mono(), None);
+        }
+    }
+
+    #[test]
+    fn decodes_blocks_sequentially_and_in_parallel_identically() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            hps.decode_blocks_sequential(&hps.blocks).unwrap(),
+            hps.decode_blocks(&hps.blocks).unwrap()
+        );
+    }
+
+    #[test]
+    fn decodes_into_reused_buffer() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let mut buf = vec![1; 10];
+        hps.decode_into(&mut buf).unwrap();
+        assert_eq!(buf, hps.decode().unwrap().samples());
+
+        let capacity = buf.capacity();
+        hps.decode_into(&mut buf).unwrap();
+        assert_eq!(buf.capacity(), capacity);
+        assert_eq!(buf, hps.decode().unwrap().samples());
+    }
+
+    #[test]
+    fn decodes_frames_into_reused_buffer() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        for block in &hps.blocks {
+            for (channel, samples) in [&mut left, &mut right].into_iter().enumerate() {
+                samples.clear();
+                Hps::decode_frames_into(
+                    block.channel_frames(channel, 2),
+                    &block.decoder_states[channel],
+                    &hps.channel_info[channel].coefficients,
+                    samples,
+                )
+                .unwrap();
+            }
+            let interleaved = left
+                .iter()
+                .zip(&right)
+                .flat_map(|(&left, &right)| [left, right])
+                .collect::<Vec<_>>();
+            assert_eq!(interleaved, hps.decode_block(block).unwrap());
+        }
+
+        // Samples are appended after whatever is already in the buffer
+        let block = &hps.blocks[0];
+        let mut samples = vec![1, 2, 3];
+        Hps::decode_frames_into(
+            &block.frames[..1],
+            &block.decoder_states[0],
+            &hps.channel_info[0].coefficients,
+            &mut samples,
+        )
+        .unwrap();
+        assert_eq!(samples[..3], [1, 2, 3]);
+        let first_frame = hps.decode_block(block).unwrap()[..SAMPLES_PER_FRAME * 2]
+            .iter()
+            .step_by(2)
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(samples[3..], first_frame);
+    }
+
+    #[test]
+    fn analyzes_blocks_as_they_are_decoded() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let mut block_indices = Vec::new();
+        let (mut sample_count, mut peak, mut energy) = (0, 0, 0);
+        hps.analyze_streaming(|index, samples| {
+            block_indices.push(index);
+            sample_count += samples.len();
+            peak = samples
+                .iter()
+                .fold(peak, |peak, s| s.unsigned_abs().max(peak));
+            energy += samples.iter().map(|&s| (s as i64).pow(2)).sum::<i64>();
+        })
+        .unwrap();
+
+        let samples = hps.decode().unwrap().samples().to_vec();
+        assert_eq!(block_indices, (0..hps.blocks.len()).collect::<Vec<_>>());
+        assert_eq!(sample_count, samples.len());
+        assert_eq!(
+            peak,
+            samples.iter().map(|s| s.unsigned_abs()).max().unwrap()
+        );
+        assert_eq!(
+            energy,
+            samples.iter().map(|&s| (s as i64).pow(2)).sum::<i64>()
+        );
+    }
+
+    #[test]
+    fn decodes_lazily() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        // Play through the song twice, so that the loop is included
+        let decoded = hps.decode().unwrap();
+        let sample_count = decoded.samples().len() * 2;
+        let expected = decoded.take(sample_count).collect::<Vec<_>>();
+
+        let lazy = hps.decode_lazy().unwrap();
+        assert_eq!(lazy.take(sample_count).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn decodes_into_ring_buffer() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        // Go around the loop at least once
+        let decoded = hps.decode().unwrap();
+        let sample_count = decoded.samples().len() + 300_000;
+        let expected = decoded.take(sample_count).collect::<Vec<_>>();
+
+        let mut cursor = DecodeCursor::new();
+        let mut ring = [0; 1000];
+        let mut write_pos = 0;
+        let mut samples = Vec::new();
+        while samples.len() < sample_count {
+            let count = 700.min(sample_count - samples.len());
+            let start = write_pos;
+            let written = hps
+                .decode_into_ring(&mut cursor, &mut ring, &mut write_pos, count)
+                .unwrap();
+            assert_eq!(written, count);
+            samples.extend((0..written).map(|i| ring[(start + i) % ring.len()]));
+        }
+
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "wav")]
+    fn encodes_wav_files() {
+        let samples = (0..16_000)
+            .flat_map(|n| {
+                let t = n as f64 / 32_000.0;
+                let sample = ((t * 440.0 * std::f64::consts::TAU).sin() * 8_000.0) as i16;
+                [sample, -sample]
+            })
+            .collect::<Vec<_>>();
+        let mut wav = std::io::Cursor::new(Vec::new());
+        DecodedHps::from_samples(samples.clone(), 32_000, 2)
+            .write_wav(&mut wav)
+            .unwrap();
+
+        let hps = Hps::from_wav_reader(wav.get_ref().as_slice()).unwrap();
+        assert_eq!(hps, Hps::from_pcm(&samples, 32_000, 2, None).unwrap());
+
+        // 8-bit audio isn't supported
+        let mut wav = std::io::Cursor::new(Vec::new());
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 32_000,
+            bits_per_sample: 8,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        writer.write_sample(0i8).unwrap();
+        writer.finalize().unwrap();
+        assert!(matches!(
+            Hps::from_wav_reader(wav.get_ref().as_slice()),
+            Err(HpsError::UnsupportedWav {
+                bits_per_sample: 8,
+                ..
+            })
+        ));
+
+        // A stereo file whose data chunk ends partway through a sample is
+        // reported as an error rather than panicking
+        let mut wav = std::io::Cursor::new(Vec::new());
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 32_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for sample in [1i16, 2, 3] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        let mut wav = wav.into_inner();
+        wav[22] = 2; // Channel count
+        wav[32] = 4; // Bytes per sample across all channels
+        wav[28..32].copy_from_slice(&(32_000u32 * 4).to_le_bytes());
+        assert!(matches!(
+            Hps::from_wav_reader(wav.as_slice()),
+            Err(HpsError::Wav(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn builds_hps_from_pcm() {
+        let samples = (0..20_000)
+            .map(|n| ((n as f64 / 32_000.0 * 440.0 * std::f64::consts::TAU).sin() * 8_000.0) as i16)
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            Hps::from_pcm(&samples, 32_000, 0, None),
+            Err(BuildError::UnsupportedChannelCount(0))
+        ));
+        assert!(matches!(
+            Hps::from_pcm(&[1, 2, 3], 32_000, 2, None),
+            Err(BuildError::PartialSample {
+                sample_count: 3,
+                channel_count: 2
+            })
+        ));
+        assert!(matches!(
+            Hps::from_pcm(&samples, 32_000, 2, Some(10_001)),
+            Err(BuildError::LoopStartOutOfRange(10_001, 10_000))
+        ));
+        assert!(matches!(
+            Hps::from_pcm(&[], 32_000, 2, None),
+            Err(BuildError::NoSamples)
+        ));
+
+        // The shortest song that can be encoded still round trips
+        let hps = Hps::from_pcm(&[1_000, -1_000], 32_000, 2, Some(0)).unwrap();
+        assert_eq!(hps.blocks.len(), 1);
+        assert_eq!(Hps::try_from(hps.to_bytes()).unwrap(), hps);
+        assert_eq!(hps.decode().unwrap().samples().len(), SAMPLES_PER_FRAME * 2);
+
+        let hps = HpsBuilder::new(&samples)
+            .channel_count(1)
+            .loop_start(5_000)
+            .build()
+            .unwrap();
+        assert_eq!(
+            hps,
+            Hps::from_pcm(&samples, 32_000, 1, Some(5_000)).unwrap()
+        );
+        assert_eq!(Hps::try_from(hps.to_bytes()).unwrap(), hps);
+        assert_eq!(hps.validate(), Ok(()));
+
+        assert!(matches!(
+            HpsBuilder::new(&samples).channel_count(3).build(),
+            Err(BuildError::UnsupportedChannelCount(3))
+        ));
+        assert!(matches!(
+            HpsBuilder::new(&samples[1..]).build(),
+            Err(BuildError::PartialSample { .. })
+        ));
+        assert!(matches!(
+            HpsBuilder::new(&samples).loop_start(10_000).build(),
+            Err(BuildError::LoopStartOutOfRange(10_000, 10_000))
+        ));
+    }
+
+    #[test]
+    fn overrides_the_loop_block() {
+        let mut hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(matches!(
+            hps.set_loop_block(Some(8)),
+            Err(LoopError::BlockOutOfRange(8, 8))
+        ));
+        assert_eq!(hps.loop_block_index, Some(2));
+
+        hps.set_loop_block(Some(4)).unwrap();
+        let decoded = hps.decode().unwrap();
+        assert_eq!(
+            decoded.loop_start_time(),
+            Some(Duration::from_nanos(
+                hps.samples_before_block(4) as u64 * 1_000_000_000 / 32_000
+            ))
+        );
+        assert_eq!(Hps::try_from(hps.to_bytes()).unwrap(), hps);
+
+        hps.disable_loop();
+        assert!(!hps.decode().unwrap().is_looping());
+        assert_eq!(Hps::try_from(hps.to_bytes()).unwrap(), hps);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn snaps_loop_to_zero_crossing() {
+        // Four full blocks, where only the third starts with the opposite sign
+        // to the last sample of the song
+        let block_length = MAX_FRAMES_PER_BLOCK * SAMPLES_PER_FRAME;
+        let samples = (0..block_length * 4)
+            .map(|i| if i / block_length == 2 { -1000 } else { 1000 })
+            .collect::<Vec<i16>>();
+        let mut hps = Hps::from_pcm(&samples, 32_000, 1, Some(block_length)).unwrap();
+        assert_eq!(hps.blocks.len(), 4);
+        assert_eq!(hps.loop_block_index, Some(1));
+
+        assert!(!hps.snap_loop_to_zero_crossing(block_length - 1).unwrap());
+        assert_eq!(hps.loop_block_index, Some(1));
+
+        assert!(hps.snap_loop_to_zero_crossing(block_length).unwrap());
+        assert_eq!(hps.loop_block_index, Some(2));
+        assert_eq!(hps.blocks[3].next_block_offset, hps.blocks[2].offset);
+        assert_eq!(hps.validate(), Ok(()));
+        assert_eq!(Hps::try_from(hps.to_bytes()).unwrap(), hps);
+
+        hps.disable_loop();
+        assert!(!hps.snap_loop_to_zero_crossing(block_length).unwrap());
+    }
+
+    #[test]
+    fn reports_sample_count_mismatches() {
+        let mut hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(hps.sample_count_mismatch(), None);
+
+        // As if the download had been cut off after 6 of the 8 blocks
+        hps.blocks.truncate(6);
+        assert_eq!(
+            hps.sample_count_mismatch(),
+            Some((384_000, hps.samples_before_block(6)))
+        );
+    }
+
+    #[test]
+    fn walks_blocks_in_play_order() {
+        let mut hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            hps.block_at_offset(hps.blocks[2].offset),
+            Some(&hps.blocks[2])
+        );
+        assert_eq!(hps.block_at_offset(0x1234), None);
+        assert!(hps.blocks_in_play_order().eq(hps.blocks.iter()));
+
+        // Swap two blocks in storage without changing how they link together
+        hps.blocks.swap(3, 4);
+        let play_order = hps
+            .blocks_in_play_order()
+            .map(|b| b.offset)
+            .collect::<Vec<_>>();
+        assert_eq!(play_order.len(), 8);
+        assert_eq!(play_order[3], hps.blocks[4].offset);
+        assert_eq!(play_order[4], hps.blocks[3].offset);
+    }
+
+    #[test]
+    fn validates_structure() {
+        for path in [
+            "test-data/test-song.hps",
+            "test-data/short-last-block-with-loop.hps",
+        ] {
+            let hps: Hps = std::fs::read(path).unwrap().try_into().unwrap();
+            assert_eq!(hps.validate(), Ok(()));
+        }
+
+        let mut hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        hps.blocks[1].next_block_offset = 0x1234;
+        hps.blocks[3].dsp_data_length += 4;
+        hps.loop_block_index = Some(3);
+        hps.channel_info[1].sample_count = 400_000;
+
+        assert_eq!(
+            hps.validate(),
+            Err(vec![
+                HpsValidationWarning::DanglingBlockLink {
+                    block_index: 1,
+                    next_block_offset: 0x1234,
+                },
+                HpsValidationWarning::PartialFrame {
+                    block_index: 3,
+                    dsp_data_length: 65540,
+                },
+                HpsValidationWarning::UnresolvedLoop(Some(3)),
+                HpsValidationWarning::SampleCountMismatch {
+                    channel: 1,
+                    sample_count: 400_000,
+                    decoded_samples: 384_048,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn round_trips_through_serde() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let json = serde_json::to_string(&hps).unwrap();
+        assert_eq!(serde_json::from_str::<Hps>(&json).unwrap(), hps);
+    }
+
+    #[test]
+    fn structure_hash_is_stable() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(hps.structure_hash(), 0x08f0_e896_a77c_db49);
+    }
+
+    #[test]
+    fn exports_loop_metadata_json() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
         assert_eq!(
-            hps.channel_info[0],
-            ChannelInfo {
-                largest_block_length: 65536,
-                sample_count: 2874134,
-                coefficients: [
-                    (492, -294),
-                    (2389, -1166),
-                    (1300, 135),
-                    (3015, -1133),
-                    (1491, -717),
-                    (2845, -1208),
-                    (1852, -11),
-                    (3692, -1705),
-                ],
-            },
+            hps.loop_metadata_json(),
+            r#"{"sample_rate":32000,"channels":2,"loop_start_sample":95984,"total_samples":384048,"duration_ms":12001}"#
         );
+
+        // A 0 Hz file has no duration
+        let mut bytes = hps.to_bytes();
+        bytes[0x08..0x0C].fill(0);
         assert_eq!(
-            hps.channel_info[1],
-            ChannelInfo {
-                largest_block_length: 65536,
-                sample_count: 2874134,
-                coefficients: [
-                    (411, -287),
-                    (2359, -1100),
-                    (1247, 143),
-                    (3147, -1288),
-                    (1472, -773),
-                    (2600, -894),
-                    (1745, 93),
-                    (3703, -1715),
-                ],
-            }
+            Hps::try_from(bytes).unwrap().loop_metadata_json(),
+            r#"{"sample_rate":0,"channels":2,"loop_start_sample":95984,"total_samples":384048,"duration_ms":null}"#
+        );
+    }
+
+    #[test]
+    fn summarizes_files() {
+        let mut hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            hps.summary(),
+            "32000 Hz, 2 channels, 8 blocks, 54864 frames, 12.001s, loops from block 2"
+        );
+
+        hps.disable_loop();
+        let mut bytes = hps.to_bytes();
+        bytes[0x08..0x0C].fill(0);
+        assert_eq!(
+            Hps::try_from(bytes).unwrap().summary(),
+            "0 Hz, 2 channels, 8 blocks, 54864 frames, unknown duration, doesn't loop"
+        );
+    }
+
+    #[test]
+    fn trims_to_exact_length() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let samples = hps.decode().unwrap().samples().to_vec();
+
+        let trimmed = hps.clone().with_exact_length();
+        let sample_count = hps.channel_info[0].sample_count as usize;
+        let expected_len = sample_count.div_ceil(SAMPLES_PER_FRAME) * SAMPLES_PER_FRAME * 2;
+        let trimmed_samples = trimmed.decode().unwrap().samples().to_vec();
+
+        assert!(trimmed_samples.len() < samples.len());
+        assert_eq!(trimmed_samples.len(), expected_len);
+        assert_eq!(trimmed_samples, samples[..expected_len]);
+        assert_eq!(trimmed.loop_block_index, hps.loop_block_index);
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn decodes_into_custom_allocator() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let samples = hps.decode_in(allocator_api2::alloc::Global).unwrap();
+        assert_eq!(samples.as_slice(), hps.decode().unwrap().samples());
+    }
+
+    #[test]
+    fn coalescing_blocks_preserves_audio() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let mut coalesced = hps.clone();
+        coalesced.coalesce_blocks(usize::MAX).unwrap();
+
+        assert!(coalesced.blocks.len() < hps.blocks.len());
+        assert_eq!(
+            coalesced.decode().unwrap().samples(),
+            hps.decode().unwrap().samples()
+        );
+        assert_eq!(coalesced.loop_metadata_json(), hps.loop_metadata_json());
+    }
+
+    #[test]
+    fn estimates_memory_usage() {
+        let hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let frame_bytes = hps
+            .blocks
+            .iter()
+            .map(|block| block.frames.len() * core::mem::size_of::<Frame>())
+            .sum::<usize>();
+        let minimum = core::mem::size_of::<Hps>()
+            + hps.blocks.len() * core::mem::size_of::<Block>()
+            + frame_bytes;
+        assert!(hps.approx_memory_bytes() >= minimum);
+    }
+
+    #[test]
+    fn repairs_damaged_coefficients() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let hps = Hps::try_from(bytes.as_slice()).unwrap();
+        let coefficients = hps.take_coefficients();
+        assert_eq!(coefficients[0], hps.channel_info[0].coefficients);
+        assert_eq!(coefficients[1], hps.channel_info[1].coefficients);
+
+        // Scramble the coefficients of both channels in the file's header
+        let mut damaged_bytes = bytes.clone();
+        for coefficients in [0x20..0x40, 0x58..0x78] {
+            damaged_bytes[coefficients].fill(0x7F);
+        }
+        let mut damaged = Hps::try_from(damaged_bytes.as_slice()).unwrap();
+        assert_ne!(damaged.take_coefficients(), coefficients);
+        assert_ne!(
+            damaged.decode().unwrap().samples(),
+            hps.decode().unwrap().samples()
+        );
+
+        damaged.set_coefficients(coefficients);
+        assert_eq!(damaged, hps);
+        assert_eq!(Hps::try_from(damaged.to_bytes()).unwrap(), hps);
+    }
+
+    #[test]
+    fn parallelizes_multi_block_files() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let mut single_block = hps.clone();
+        single_block.blocks.truncate(1);
+        single_block.loop_block_index = None;
+
+        #[cfg(feature = "parallel")]
+        {
+            let pool = |threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap()
+            };
+            assert!(pool(2).install(|| hps.will_parallelize()));
+            assert!(!pool(2).install(|| single_block.will_parallelize()));
+            assert!(!pool(1).install(|| hps.will_parallelize()));
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            assert!(!hps.will_parallelize());
+            assert!(!single_block.will_parallelize());
+        }
+    }
+
+    #[test]
+    fn lists_distinct_frame_headers() {
+        let mut hps: Hps = std::fs::read("test-data/test-song.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let headers = hps
+            .blocks
+            .iter()
+            .flat_map(|block| &block.frames)
+            .map(|frame| frame.header)
+            .collect::<Vec<_>>();
+
+        // Every coefficient index (the high nibble) is used with most scales
+        let variety = hps.frame_header_variety();
+        assert_eq!(variety.len(), 91);
+        assert_eq!(variety.first(), Some(&0x00));
+        assert_eq!(variety.last(), Some(&0x7C));
+        assert!(headers.iter().all(|header| variety.contains(header)));
+        assert!(variety.iter().all(|header| headers.contains(header)));
+
+        let quiet: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(quiet.frame_header_variety(), BTreeSet::from([0x00]));
+
+        // Rewriting every frame to use the same header leaves only that one
+        for frame in hps.blocks.iter_mut().flat_map(|block| &mut block.frames) {
+            frame.header = 0x12;
+        }
+        assert_eq!(hps.frame_header_variety(), BTreeSet::from([0x12]));
+    }
+
+    #[test]
+    fn finds_gaps_between_blocks() {
+        let bytes = std::fs::read("test-data/short-last-block-with-loop.hps").unwrap();
+        let hps = Hps::try_from(bytes.as_slice()).unwrap();
+        // The last block links back to the loop block, which isn't a gap
+        assert_eq!(hps.offset_gaps(), Vec::new());
+
+        // Link the first block past the second, leaving it as unused bytes
+        let [first_block, second_block, third_block, ..] = &hps.blocks[..] else {
+            unreachable!()
+        };
+        let mut skipping = bytes.clone();
+        let link = first_block.offset as usize + 0x08;
+        skipping[link..link + 4].copy_from_slice(&third_block.offset.to_be_bytes());
+
+        let parsed = Hps::try_from(skipping.as_slice()).unwrap();
+        assert_eq!(parsed.blocks.len(), 7);
+        assert_eq!(
+            parsed.offset_gaps(),
+            vec![(second_block.offset, third_block.offset)]
+        );
+
+        // The skipped block is still a gap when it's kept
+        let kept = Hps::try_from_keep_all(&skipping).unwrap();
+        assert_eq!(kept.blocks.len(), 8);
+        assert_eq!(kept.offset_gaps(), parsed.offset_gaps());
+    }
+
+    #[test]
+    fn counts_block_sizes() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        // Full size blocks, a shorter one leading up to the loop block, and the
+        // short last block
+        assert_eq!(
+            hps.block_size_histogram(),
+            BTreeMap::from([(0x600, 1), (0xAC80, 1), (0x10000, 6)])
+        );
+        assert_eq!(
+            hps.block_size_histogram().values().sum::<usize>(),
+            hps.blocks.len()
         );
     }
 
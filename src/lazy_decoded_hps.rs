@@ -0,0 +1,57 @@
+//! Contains [`LazyDecodedHps`] for iterating over decoded PCM samples without
+//! decoding the whole song up front. Only a single block's worth of samples is
+//! held in memory at once, which keeps memory usage low for long songs.
+//!
+//! ```
+//! let hps: Hps = std::fs::read("./respect-your-elders.hps")?.try_into()?;
+//! let audio: LazyDecodedHps = hps.decode_lazy()?;
+//!
+//! // For looping songs, this will go on forever:
+//! for sample in audio {
+//!     println!("{sample}");
+//! }
+//! ```
+
+use crate::errors::HpsDecodeError;
+use crate::hps::{DecodeCursor, Hps};
+
+/// An iterator over the decoded PCM samples of an [`Hps`], which decodes one
+/// block at a time as it's iterated over. It yields exactly the same samples
+/// as a [`DecodedHps`](crate::decoded_hps::DecodedHps).
+///
+/// For general usage, see the [module-level documentation.](crate::lazy_decoded_hps)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LazyDecodedHps {
+    hps: Hps,
+    cursor: DecodeCursor,
+}
+
+impl LazyDecodedHps {
+    /// Create an iterator over the samples of `hps`, looping it if it has a
+    /// loop block.
+    ///
+    /// Every frame header is checked up front with
+    /// [`Hps::validate_frame_headers`], so that decoding can't fail partway
+    /// through iteration.
+    pub fn new(hps: Hps) -> Result<Self, HpsDecodeError> {
+        hps.validate_frame_headers()?;
+        Ok(Self {
+            hps,
+            cursor: DecodeCursor::new(),
+        })
+    }
+
+    /// Get the [`Hps`] being decoded
+    pub fn hps(&self) -> &Hps {
+        &self.hps
+    }
+}
+
+impl Iterator for LazyDecodedHps {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Frame headers were validated up front, so decoding can't fail here
+        self.cursor.next_sample(&self.hps).ok().flatten()
+    }
+}
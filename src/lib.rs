@@ -20,7 +20,7 @@
 //!
 //! fn main() -> Result<(), Box<dyn Error>> {
 //!     // Decode an .hps file into PCM samples for playback
-//!     let hps: Hps = std::fs::read("./respect-your-elders.hps")?.try_into()?;
+//!     let hps = Hps::try_from_path("./respect-your-elders.hps")?;
 //!     let audio = hps.decode()?;
 //!
 //!     // Play the song with the rodio library
@@ -35,14 +35,59 @@
 //! }
 //! ```
 //!
+//! # `no_std` Support
+//!
+//! Parsing and decoding only need `alloc`, so the crate can be used without
+//! the standard library by disabling the default `std` feature:
+//!
+//! ```toml
+//! [dependencies]
+//! hps_decode = { version = "0.2.1", default-features = false }
+//! ```
+//!
+//! Without `std`, anything that reads from [`std::io`] or needs floating point
+//! math from the standard library (such as [`Hps::from_pcm`] and loudness
+//! analysis) is unavailable.
+//!
+//! # Parallel Decoding
+//!
+//! By default, blocks are decoded in parallel with
+//! [rayon.](https://docs.rs/rayon) If you're already decoding several files at
+//! once, or targeting a platform without threads, disable the default
+//! `parallel` feature to decode on the current thread instead. The decoded
+//! samples are identical either way.
+//!
 //! # .HPS File Layout
 //! For general purpose, language agnostic documentation of the `.hps` file format,
 //! [see here.](https://github.com/DarylPinto/hps_decode/blob/main/HPS-LAYOUT.md)
 
-mod errors;
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+mod checksum;
 mod parsers;
+mod writers;
 
+pub use errors::HpsError;
 pub use hps::Hps;
 
+/// Read, parse and decode the `.hps` file at `path` in one step.
+///
+/// ```
+/// let audio = hps_decode::from_path("./respect-your-elders.hps")?;
+/// ```
+#[cfg(feature = "std")]
+pub fn from_path<P: AsRef<std::path::Path>>(path: P) -> Result<decoded_hps::DecodedHps, HpsError> {
+    Ok(Hps::try_from_path(path)?.decode()?)
+}
+
 pub mod decoded_hps;
+pub mod encoder;
+pub mod errors;
 pub mod hps;
+pub mod lazy_decoded_hps;
+#[cfg(feature = "rodio-source")]
+pub mod streaming_hps_source;
+#[cfg(feature = "symphonia-source")]
+pub mod symphonia_source;
@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use winnow::{
     binary::{be_i16, be_u32, be_u8},
     combinator::repeat,
@@ -10,16 +11,19 @@ use crate::errors::HpsParseError;
 use crate::hps::{Block, ChannelInfo, DSPDecoderState, Frame, COEFFICIENT_PAIRS_PER_CHANNEL};
 use winnow::prelude::*;
 
+/// The first 8 bytes of every `.hps` file
+pub(crate) const MAGIC_NUMBER: &[u8] = b" HALPST\0";
+
 pub(crate) fn parse_file_header(bytes: &mut &[u8]) -> Result<(u32, u32), HpsParseError> {
     use HpsParseError::*;
 
-    let _ = literal(" HALPST\0")
+    let _ = literal(MAGIC_NUMBER)
         .parse_next(bytes)
         .map_err(|_: ErrMode<ContextError>| InvalidMagicNumber)?;
     let sample_rate = be_u32.parse_next(bytes)?;
     let channel_count = be_u32.parse_next(bytes)?;
 
-    if channel_count != 2 {
+    if !matches!(channel_count, 1 | 2) {
         return Err(UnsupportedChannelCount(channel_count));
     }
 
@@ -33,7 +37,7 @@ pub(crate) fn parse_channel_info(bytes: &mut &[u8]) -> PResult<ChannelInfo> {
     let _ = take(4usize).parse_next(bytes)?;
     let coefficients: Vec<(i16, i16)> =
         repeat(1..=COEFFICIENT_PAIRS_PER_CHANNEL, seq!((be_i16, be_i16))).parse_next(bytes)?;
-    let _dsp_decoder_state = take(8usize).parse_next(bytes)?;
+    let initial_decoder_state = parse_dsp_decoder_state(bytes)?;
 
     Ok(ChannelInfo {
         largest_block_length,
@@ -44,6 +48,7 @@ pub(crate) fn parse_channel_info(bytes: &mut &[u8]) -> PResult<ChannelInfo> {
             // CHANNEL_COEFFICIENT_PAIR_COUNT
             unreachable!()
         }),
+        initial_decoder_state,
     })
 }
 
@@ -72,17 +77,18 @@ pub(crate) fn parse_block(file_size: usize) -> impl FnMut(&mut &[u8]) -> PResult
 
 #[inline]
 fn parse_dsp_decoder_state(bytes: &mut &[u8]) -> PResult<DSPDecoderState> {
-    let _ps_hi = take(1usize).parse_next(bytes)?;
-    let _ps = take(1usize).parse_next(bytes)?;
+    let ps_hi = be_u8.parse_next(bytes)?;
+    let ps = be_u8.parse_next(bytes)?;
     let initial_hist_1 = be_i16.parse_next(bytes)?;
     let initial_hist_2 = be_i16.parse_next(bytes)?;
-    let _ = take(2usize).parse_next(bytes)?;
+    let gain = be_i16.parse_next(bytes)?;
 
     Ok(DSPDecoderState {
-        // ps_hi,
-        // ps,
+        ps_hi,
+        ps,
         initial_hist_1,
         initial_hist_2,
+        gain,
     })
 }
 
@@ -0,0 +1,97 @@
+//! Contains [`StreamingHpsSource`], a rodio source that decodes an [`Hps`] one
+//! block at a time as it's played, instead of decoding the whole song up front
+//! like [`Hps::decode`] does.
+//!
+//! This makes playback start sooner and keeps memory usage low, which matters
+//! most for long songs:
+//! ```
+//! let hps: Hps = std::fs::read("./respect-your-elders.hps")?.try_into()?;
+//! let source = StreamingHpsSource::new(hps)?;
+//!
+//! sink.append(source);
+//! ```
+
+use crate::errors::HpsDecodeError;
+use crate::hps::Hps;
+use crate::lazy_decoded_hps::LazyDecodedHps;
+
+/// A rodio source that lazily decodes an [`Hps`] block by block.
+///
+/// For general usage, see the [module-level documentation.](crate::streaming_hps_source)
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamingHpsSource {
+    inner: LazyDecodedHps,
+}
+
+impl StreamingHpsSource {
+    /// Create a source that plays `hps`, looping it if it has a loop block.
+    ///
+    /// Every frame header is checked up front with
+    /// [`Hps::validate_frame_headers`], so that decoding can't fail partway
+    /// through playback.
+    pub fn new(hps: Hps) -> Result<Self, HpsDecodeError> {
+        Ok(Self {
+            inner: LazyDecodedHps::new(hps)?,
+        })
+    }
+
+    /// Get the [`Hps`] being played
+    pub fn hps(&self) -> &Hps {
+        self.inner.hps()
+    }
+}
+
+impl Iterator for StreamingHpsSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl rodio::Source for StreamingHpsSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+    fn channels(&self) -> u16 {
+        self.hps().channel_count as u16
+    }
+    fn sample_rate(&self) -> u32 {
+        self.hps().sample_rate
+    }
+    fn total_duration(&self) -> Option<core::time::Duration> {
+        if self.hps().loop_block_index.is_some() {
+            None
+        } else {
+            let sample_count = self.hps().samples_before_block(self.hps().blocks.len()) as u64;
+            Some(core::time::Duration::from_nanos(
+                sample_count * 1_000_000_000 / self.hps().sample_rate as u64,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_the_same_samples_as_decode() {
+        let hps: Hps = std::fs::read("test-data/short-last-block-with-loop.hps")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        // Play through the song twice, so that the loop is included
+        let decoded = hps.decode().unwrap();
+        let sample_count = decoded.samples().len() * 2;
+        let expected = decoded.take(sample_count).collect::<Vec<_>>();
+
+        let streamed = StreamingHpsSource::new(hps)
+            .unwrap()
+            .take(sample_count)
+            .collect::<Vec<_>>();
+
+        assert_eq!(streamed, expected);
+    }
+}
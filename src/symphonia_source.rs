@@ -0,0 +1,372 @@
+//! Contains [`HpsReader`] and [`HpsDecoder`], which let
+//! [symphonia](https://docs.rs/symphonia) demux and decode `.hps` files like
+//! any other format.
+//!
+//! Each [`Block`](crate::hps::Block) becomes one packet, and the song plays
+//! through once without looping. Register both with symphonia to probe and
+//! decode `.hps` files:
+//! ```
+//! let mut probe = Probe::default();
+//! probe.register_all::<HpsReader>();
+//!
+//! let mut codecs = CodecRegistry::new();
+//! codecs.register_all::<HpsDecoder>();
+//! ```
+
+use std::io::Read;
+
+use symphonia_core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec,
+};
+use symphonia_core::codecs::{
+    decl_codec_type, CodecDescriptor, CodecParameters, CodecType, Decoder, DecoderOptions,
+    FinalizeResult,
+};
+use symphonia_core::errors::{
+    decode_error, end_of_stream_error, seek_error, unsupported_error, Result, SeekErrorKind,
+};
+use symphonia_core::formats::{
+    Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+};
+use symphonia_core::io::MediaSourceStream;
+use symphonia_core::meta::{Metadata, MetadataLog};
+use symphonia_core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia_core::sample::SampleFormat;
+use symphonia_core::units::TimeBase;
+
+use crate::hps::{Hps, SAMPLES_PER_FRAME};
+use crate::parsers::{parse_block, parse_channel_info, parse_file_header};
+
+/// The codec type of the DSP ADPCM audio in `.hps` files
+pub const CODEC_TYPE_HPS: CodecType = decl_codec_type(b"hps");
+
+/// Size of the file header and channel info, which are passed to the decoder
+/// as the track's extra data
+const HEADER_SIZE: usize = 0x80;
+const BLOCK_HEADER_SIZE: usize = 0x20;
+
+/// A symphonia [`FormatReader`] for `.hps` files.
+///
+/// For general usage, see the [module-level documentation.](crate::symphonia_source)
+pub struct HpsReader {
+    source: MediaSourceStream,
+    bytes: Vec<u8>,
+    tracks: Vec<Track>,
+    metadata: MetadataLog,
+    /// Byte range and first sample (per channel) of each block
+    packets: Vec<(core::ops::Range<usize>, u64)>,
+    next_packet_index: usize,
+}
+
+impl QueryDescriptor for HpsReader {
+    fn query() -> &'static [Descriptor] {
+        &[Descriptor {
+            short_name: "hps",
+            long_name: "HAL Laboratory HPS",
+            extensions: &["hps"],
+            mime_types: &[],
+            markers: &[b" HALPST\0"],
+            score: Self::score,
+            inst: Instantiate::Format(|source, options| {
+                Ok(Box::new(HpsReader::try_new(source, options)?))
+            }),
+        }]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl FormatReader for HpsReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let mut bytes = Vec::new();
+        source.read_to_end(&mut bytes)?;
+        let Ok(hps) = Hps::try_from(bytes.as_slice()) else {
+            return decode_error("hps: invalid file");
+        };
+
+        let packets = hps
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| {
+                let start = block.offset as usize;
+                let end = start + BLOCK_HEADER_SIZE + block.dsp_data_length as usize;
+                (start..end, hps.samples_before_block(index) as u64)
+            })
+            .collect();
+
+        let channel_count = hps.channel_count as usize;
+        let max_frames_per_packet = hps
+            .blocks
+            .iter()
+            .map(|block| block.channel_sample_count(channel_count))
+            .max()
+            .unwrap_or_default();
+
+        let mut codec_params = CodecParameters::new();
+        codec_params
+            .for_codec(CODEC_TYPE_HPS)
+            .with_sample_rate(hps.sample_rate)
+            .with_time_base(TimeBase::new(1, hps.sample_rate))
+            .with_n_frames(hps.samples_before_block(hps.blocks.len()) as u64)
+            .with_sample_format(SampleFormat::S16)
+            .with_bits_per_sample(16)
+            .with_bits_per_coded_sample(4)
+            .with_channels(channels(hps.channel_count))
+            .with_max_frames_per_packet(max_frames_per_packet as u64)
+            .with_frames_per_block(SAMPLES_PER_FRAME as u64)
+            .with_extra_data(bytes[..HEADER_SIZE].into());
+
+        Ok(Self {
+            source,
+            bytes,
+            tracks: vec![Track::new(0, codec_params)],
+            metadata: MetadataLog::default(),
+            packets,
+            next_packet_index: 0,
+        })
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &[]
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    /// Seeks to the start of the block containing the requested time, which
+    /// is always at or before it.
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        let codec_params = &self.tracks[0].codec_params;
+        let required_ts = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => codec_params.time_base.unwrap().calc_timestamp(time),
+        };
+        if required_ts >= codec_params.n_frames.unwrap_or_default() {
+            return seek_error(SeekErrorKind::OutOfRange);
+        }
+
+        self.next_packet_index = self
+            .packets
+            .partition_point(|(_, first_sample)| *first_sample <= required_ts)
+            .saturating_sub(1);
+
+        Ok(SeekedTo {
+            track_id: 0,
+            required_ts,
+            actual_ts: self.packets[self.next_packet_index].1,
+        })
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        let Some((range, first_sample)) = self.packets.get(self.next_packet_index) else {
+            return end_of_stream_error();
+        };
+        let duration = self.packets.get(self.next_packet_index + 1).map_or(
+            self.tracks[0].codec_params.n_frames.unwrap_or_default(),
+            |p| p.1,
+        ) - first_sample;
+        self.next_packet_index += 1;
+
+        Ok(Packet::new_from_slice(
+            0,
+            *first_sample,
+            duration,
+            &self.bytes[range.clone()],
+        ))
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
+
+/// A symphonia [`Decoder`] for the packets produced by [`HpsReader`].
+///
+/// Every block stores the decoder state it starts with, so packets can be
+/// decoded in any order.
+///
+/// For general usage, see the [module-level documentation.](crate::symphonia_source)
+pub struct HpsDecoder {
+    params: CodecParameters,
+    /// The file header and channel info, with no blocks
+    hps: Hps,
+    buffer: AudioBuffer<i16>,
+}
+
+impl Decoder for HpsDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        if params.codec != CODEC_TYPE_HPS {
+            return unsupported_error("hps: invalid codec type");
+        }
+        let Some(mut header) = params.extra_data.as_deref() else {
+            return decode_error("hps: missing file header");
+        };
+        let Ok((sample_rate, channel_count)) = parse_file_header(&mut header) else {
+            return decode_error("hps: invalid file header");
+        };
+        let (Ok(left), Ok(right)) = (
+            parse_channel_info(&mut header),
+            parse_channel_info(&mut header),
+        ) else {
+            return decode_error("hps: invalid channel info");
+        };
+
+        let hps = Hps {
+            sample_rate,
+            channel_count,
+            channel_info: [left, right],
+            blocks: Vec::new(),
+            loop_block_index: None,
+        };
+        let max_frames = params.max_frames_per_packet.unwrap_or_default();
+        let spec = SignalSpec::new(sample_rate, channels(channel_count));
+
+        Ok(Self {
+            params: params.clone(),
+            hps,
+            buffer: AudioBuffer::new(max_frames, spec),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[CodecDescriptor {
+            codec: CODEC_TYPE_HPS,
+            short_name: "hps",
+            long_name: "Nintendo DSP ADPCM (HAL Laboratory HPS)",
+            inst_func: |params, options| Ok(Box::new(HpsDecoder::try_new(params, options)?)),
+        }]
+    }
+
+    fn reset(&mut self) {}
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buffer.clear();
+
+        let mut data = packet.buf();
+        let Ok(block) = parse_block(data.len())(&mut data) else {
+            return decode_error("hps: invalid block");
+        };
+        let Ok(samples) = self.hps.decode_block(&block) else {
+            return decode_error("hps: invalid frame header");
+        };
+
+        let channel_count = self.hps.channel_count as usize;
+        let frame_count = samples.len() / channel_count;
+        if frame_count as u64 > self.buffer.capacity() as u64 {
+            self.buffer = AudioBuffer::new(frame_count as u64, *self.buffer.spec());
+        }
+        self.buffer.render_reserved(Some(frame_count));
+        for channel in 0..channel_count {
+            let channel_samples = samples.iter().skip(channel).step_by(channel_count);
+            for (dest, &sample) in self
+                .buffer
+                .chan_mut(channel)
+                .iter_mut()
+                .zip(channel_samples)
+            {
+                *dest = sample;
+            }
+        }
+
+        Ok(self.buffer.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
+
+fn channels(channel_count: u32) -> Channels {
+    match channel_count {
+        1 => Channels::FRONT_LEFT,
+        _ => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia_core::io::MediaSourceStreamOptions;
+    use symphonia_core::meta::MetadataOptions;
+    use symphonia_core::probe::{Hint, Probe};
+
+    #[test]
+    fn decodes_the_same_samples_as_decode() {
+        let bytes = std::fs::read("test-data/short-last-block-with-loop.hps").unwrap();
+        let expected = Hps::try_from(bytes.as_slice()).unwrap().decode().unwrap();
+
+        let source = MediaSourceStream::new(
+            Box::new(std::io::Cursor::new(bytes)),
+            MediaSourceStreamOptions::default(),
+        );
+        let mut reader = HpsReader::try_new(source, &FormatOptions::default()).unwrap();
+        let codec_params = &reader.default_track().unwrap().codec_params;
+        let mut decoder = HpsDecoder::try_new(codec_params, &DecoderOptions::default()).unwrap();
+
+        let mut samples = Vec::new();
+        while let Ok(packet) = reader.next_packet() {
+            let AudioBufferRef::S16(buffer) = decoder.decode(&packet).unwrap() else {
+                unreachable!()
+            };
+            for frame in 0..buffer.frames() {
+                samples.extend([buffer.chan(0)[frame], buffer.chan(1)[frame]]);
+            }
+        }
+        assert_eq!(samples, expected.samples());
+
+        // Seeking lands on the start of the block containing the timestamp
+        let seeked_to = reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: 100_000,
+                    track_id: 0,
+                },
+            )
+            .unwrap();
+        let packet = reader.next_packet().unwrap();
+        assert!(seeked_to.actual_ts <= 100_000);
+        assert_eq!(packet.ts, seeked_to.actual_ts);
+        assert!(100_000 < packet.ts + packet.dur);
+    }
+
+    #[test]
+    fn probes_hps_files() {
+        let bytes = std::fs::read("test-data/test-song.hps").unwrap();
+        let source = MediaSourceStream::new(
+            Box::new(std::io::Cursor::new(bytes)),
+            MediaSourceStreamOptions::default(),
+        );
+
+        let mut probe = Probe::default();
+        probe.register_all::<HpsReader>();
+        let probed = probe
+            .format(
+                &Hint::new(),
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap();
+
+        let codec_params = &probed.format.default_track().unwrap().codec_params;
+        assert_eq!(codec_params.codec, CODEC_TYPE_HPS);
+        assert_eq!(codec_params.n_frames, Some(2_874_144));
+    }
+}
@@ -0,0 +1,61 @@
+use alloc::vec::Vec;
+
+use crate::hps::{Block, ChannelInfo, DSPDecoderState, BYTES_PER_FRAME};
+use crate::parsers::MAGIC_NUMBER;
+
+pub(crate) fn write_file_header(bytes: &mut Vec<u8>, sample_rate: u32, channel_count: u32) {
+    bytes.extend_from_slice(MAGIC_NUMBER);
+    bytes.extend_from_slice(&sample_rate.to_be_bytes());
+    bytes.extend_from_slice(&channel_count.to_be_bytes());
+}
+
+pub(crate) fn write_channel_info(bytes: &mut Vec<u8>, channel_info: &ChannelInfo) {
+    bytes.extend_from_slice(&channel_info.largest_block_length.to_be_bytes());
+    bytes.extend_from_slice(&2u32.to_be_bytes());
+    bytes.extend_from_slice(&channel_info.sample_count.to_be_bytes());
+    bytes.extend_from_slice(&2u32.to_be_bytes());
+    for (coef1, coef2) in channel_info.coefficients {
+        bytes.extend_from_slice(&coef1.to_be_bytes());
+        bytes.extend_from_slice(&coef2.to_be_bytes());
+    }
+    write_dsp_decoder_state(bytes, &channel_info.initial_decoder_state);
+}
+
+/// Write a block, where `end_address` is the nibble address of the last
+/// sample in each channel's frames.
+///
+/// The predictor/scale of each channel's decoder state is taken from the
+/// header of its first frame, so it can't go stale when frames are rewritten.
+pub(crate) fn write_block(
+    bytes: &mut Vec<u8>,
+    block: &Block,
+    channel_count: usize,
+    end_address: u32,
+    next_block_offset: u32,
+) {
+    let dsp_data_length = (block.frames.len() * BYTES_PER_FRAME) as u32;
+    bytes.extend_from_slice(&dsp_data_length.to_be_bytes());
+    bytes.extend_from_slice(&end_address.to_be_bytes());
+    bytes.extend_from_slice(&next_block_offset.to_be_bytes());
+    for (channel, decoder_state) in block.decoder_states.iter().enumerate() {
+        let first_frame = block.channel_frames(channel, channel_count).first();
+        let decoder_state = DSPDecoderState {
+            ps: first_frame.map_or(decoder_state.ps, |frame| frame.header),
+            ..decoder_state.clone()
+        };
+        write_dsp_decoder_state(bytes, &decoder_state);
+    }
+    bytes.extend_from_slice(&[0; 4]);
+    for frame in &block.frames {
+        bytes.push(frame.header);
+        bytes.extend_from_slice(&frame.encoded_sample_data);
+    }
+}
+
+fn write_dsp_decoder_state(bytes: &mut Vec<u8>, decoder_state: &DSPDecoderState) {
+    bytes.push(decoder_state.ps_hi);
+    bytes.push(decoder_state.ps);
+    bytes.extend_from_slice(&decoder_state.initial_hist_1.to_be_bytes());
+    bytes.extend_from_slice(&decoder_state.initial_hist_2.to_be_bytes());
+    bytes.extend_from_slice(&decoder_state.gain.to_be_bytes());
+}